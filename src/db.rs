@@ -1,8 +1,41 @@
+use crate::download_item::auth::Credentials;
+use crate::download_item::checksum::{Checksum, PieceHashes};
+use crate::download_item::collision::CollisionPolicy;
+use crate::download_item::download::{Chunk, ChunkStatus, Validators};
+use crate::download_item::headers::RequestHeaders;
+use crate::download_item::mirrors::Mirror;
+use crate::download_item::throttle::TokenBucket;
 use crate::download_item::{DownloadItem, DownloadStatus};
-use rusqlite::{Connection, Result};
+use crate::scheduler::{Schedule, ScheduleAction};
+use crate::settings;
+use crate::torrent::metainfo::Metainfo;
+use crate::torrent::{TorrentItem, TorrentStatus};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Where the database lived before it moved to the data directory.
+const LEGACY_DB_PATH: &str = "downloads.db";
+
+/// Opens the database in the data directory, wherever hedgehog was started from.
+pub fn open() -> Result<Connection> {
+    Connection::open(settings::db_path())
+}
 
 pub fn init_db() -> Result<Connection> {
-    let conn = Connection::open("downloads.db")?;
+    let db_path = settings::db_path();
+    if let Err(e) = std::fs::create_dir_all(settings::data_dir()) {
+        log::error!("Couldn't create {}: {}", settings::data_dir().display(), e);
+    }
+    // Carry the history over from a database left in the working directory.
+    if !db_path.exists() && Path::new(LEGACY_DB_PATH).exists() {
+        match std::fs::copy(LEGACY_DB_PATH, &db_path) {
+            Ok(_) => log::info!("Copied {} to {}", LEGACY_DB_PATH, db_path.display()),
+            Err(e) => log::error!("Couldn't copy {}: {}", LEGACY_DB_PATH, e),
+        }
+    }
+
+    let conn = open()?;
 
     conn.execute(
         "CREATE TABLE IF NOT EXISTS downloads (
@@ -30,14 +63,194 @@ pub fn init_db() -> Result<Connection> {
         [],
     )?;
 
+    add_column_if_missing(&conn, "chunks", "downloaded_bytes", "INTEGER DEFAULT 0")?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS mirrors (
+            id INTEGER PRIMARY KEY,
+            download_id INTEGER,
+            position INTEGER,
+            url TEXT NOT NULL,
+            FOREIGN KEY(download_id) REFERENCES downloads(id)
+        )",
+        [],
+    )?;
+    add_column_if_missing(&conn, "mirrors", "priority", "INTEGER")?;
+    add_column_if_missing(&conn, "mirrors", "location", "TEXT")?;
+
+    // Logins stay out of `downloads`, one row per download or per host.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS credentials (
+            id INTEGER PRIMARY KEY,
+            download_id INTEGER UNIQUE,
+            host TEXT UNIQUE,
+            username TEXT NOT NULL,
+            password TEXT NOT NULL,
+            FOREIGN KEY(download_id) REFERENCES downloads(id)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schedules (
+            id INTEGER PRIMARY KEY,
+            days INTEGER NOT NULL,
+            start_minute INTEGER NOT NULL,
+            end_minute INTEGER NOT NULL,
+            action TEXT NOT NULL,
+            speed_limit INTEGER
+        )",
+        [],
+    )?;
+
+    // Torrents keep their `.torrent` file, which is parsed again on load.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS torrents (
+            id INTEGER PRIMARY KEY,
+            info_hash TEXT NOT NULL UNIQUE,
+            name TEXT NOT NULL,
+            total_size INTEGER NOT NULL,
+            metainfo BLOB NOT NULL,
+            download_dir TEXT,
+            status TEXT NOT NULL,
+            downloaded_bytes INTEGER DEFAULT 0,
+            uploaded_bytes INTEGER DEFAULT 0,
+            priority INTEGER DEFAULT 0,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )?;
+
+    add_column_if_missing(&conn, "downloads", "checksum", "TEXT")?;
+    add_column_if_missing(&conn, "downloads", "speed_limit", "INTEGER")?;
+    add_column_if_missing(&conn, "downloads", "priority", "INTEGER DEFAULT 0")?;
+    add_column_if_missing(&conn, "downloads", "collision_policy", "TEXT")?;
+    add_column_if_missing(&conn, "downloads", "download_dir", "TEXT")?;
+    add_column_if_missing(&conn, "downloads", "etag", "TEXT")?;
+    add_column_if_missing(&conn, "downloads", "last_modified", "TEXT")?;
+    add_column_if_missing(&conn, "downloads", "supports_ranges", "INTEGER")?;
+    add_column_if_missing(&conn, "downloads", "file_name", "TEXT")?;
+    add_column_if_missing(&conn, "downloads", "pieces", "TEXT")?;
+    add_column_if_missing(&conn, "downloads", "headers", "TEXT")?;
+    add_column_if_missing(&conn, "downloads", "cookie", "TEXT")?;
+    add_column_if_missing(&conn, "downloads", "user_agent", "TEXT")?;
+    add_column_if_missing(&conn, "downloads", "referer", "TEXT")?;
+    add_column_if_missing(&conn, "downloads", "expected_size", "INTEGER")?;
+
     Ok(conn)
 }
 
+/// `CREATE TABLE IF NOT EXISTS` leaves tables from older versions alone, so
+/// columns added later have to be patched in.
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+            table
+        ))?
+        .exists([column])?;
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+pub fn load_setting(conn: &Connection, key: &str) -> Result<Option<String>> {
+    conn.query_row("SELECT value FROM settings WHERE key = ?1", [key], |row| {
+        row.get(0)
+    })
+    .optional()
+}
+
+pub fn save_setting(conn: &Connection, key: &str, value: Option<&str>) -> Result<()> {
+    match value {
+        Some(value) => conn.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+            [key, value],
+        )?,
+        None => conn.execute("DELETE FROM settings WHERE key = ?1", [key])?,
+    };
+    Ok(())
+}
+
+/// Inserts a schedule and returns the id it was given.
+pub fn insert_schedule(conn: &Connection, schedule: &Schedule) -> Result<i64> {
+    let (action, speed_limit) = match schedule.action {
+        ScheduleAction::Allow => ("Allow", None),
+        ScheduleAction::Pause => ("Pause", None),
+        ScheduleAction::Throttle(limit) => ("Throttle", Some(limit)),
+    };
+
+    conn.execute(
+        "INSERT INTO schedules (days, start_minute, end_minute, action, speed_limit)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        (
+            schedule.days,
+            schedule.start,
+            schedule.end,
+            action,
+            speed_limit,
+        ),
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn delete_schedule(conn: &Connection, id: i64) -> Result<()> {
+    conn.execute("DELETE FROM schedules WHERE id = ?1", [id])?;
+    Ok(())
+}
+
+pub fn load_schedules(conn: &Connection) -> Result<Vec<Schedule>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, days, start_minute, end_minute, action, speed_limit FROM schedules ORDER BY id",
+    )?;
+
+    let schedules = stmt.query_map([], |row| {
+        let action: String = row.get(4)?;
+        let speed_limit: Option<u64> = row.get(5)?;
+        let action = match (action.as_str(), speed_limit) {
+            ("Throttle", Some(limit)) => ScheduleAction::Throttle(limit),
+            ("Pause", _) => ScheduleAction::Pause,
+            _ => ScheduleAction::Allow,
+        };
+
+        Ok(Schedule {
+            id: row.get(0)?,
+            days: row.get(1)?,
+            start: row.get(2)?,
+            end: row.get(3)?,
+            action,
+        })
+    })?;
+
+    schedules.collect()
+}
+
 pub fn save_download(conn: &Connection, item: &DownloadItem) -> Result<()> {
     let (status_str, downloaded_bytes) = match &item.status {
         DownloadStatus::InProgress {
             downloaded_bytes, ..
         } => ("InProgress".to_string(), *downloaded_bytes),
+        DownloadStatus::Paused {
+            downloaded_bytes, ..
+        } => ("Paused".to_string(), *downloaded_bytes),
         DownloadStatus::Completed => ("Completed".to_string(), item.total_size.unwrap_or(0) as u64),
         _ => (item.status.to_string(), 0),
     };
@@ -48,23 +261,188 @@ pub fn save_download(conn: &Connection, item: &DownloadItem) -> Result<()> {
     ); // Debug log
 
     conn.execute(
-        "INSERT OR REPLACE INTO downloads (id, url, file_path, total_size, status, downloaded_bytes) 
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-        (
+        "INSERT OR REPLACE INTO downloads (id, url, file_path, total_size, status, downloaded_bytes, checksum, speed_limit, priority, collision_policy, download_dir, etag, last_modified, supports_ranges, file_name, pieces, headers, cookie, user_agent, referer, expected_size) 
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21)",
+        params![
             item.id,
             &item.url,
             &item.file_path,
             item.total_size,
             status_str,
             downloaded_bytes,
-        ),
+            item.checksum.as_ref().map(Checksum::to_string),
+            item.speed_limit,
+            item.priority,
+            item.collision_policy.map(|policy| policy.as_str()),
+            &item.download_dir,
+            &item.validators.etag,
+            &item.validators.last_modified,
+            item.supports_ranges,
+            &item.file_name,
+            item.pieces.as_ref().map(PieceHashes::to_string),
+            Some(item.headers.headers_text()).filter(|text| !text.is_empty()),
+            &item.headers.cookie,
+            &item.headers.user_agent,
+            &item.headers.referer,
+            item.expected_size,
+        ],
     )?;
+
+    save_mirrors(conn, item.id, &item.mirrors)?;
+    save_credentials(conn, item.id, item.headers.credentials.as_ref())?;
+    save_chunks(conn, item.id, &item.chunks)
+}
+
+pub fn delete_download(conn: &Connection, id: i64) -> Result<()> {
+    conn.execute("DELETE FROM chunks WHERE download_id = ?1", [id])?;
+    conn.execute("DELETE FROM mirrors WHERE download_id = ?1", [id])?;
+    conn.execute("DELETE FROM credentials WHERE download_id = ?1", [id])?;
+    conn.execute("DELETE FROM downloads WHERE id = ?1", [id])?;
     Ok(())
 }
 
+fn save_mirrors(conn: &Connection, download_id: i64, mirrors: &[Mirror]) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM mirrors WHERE download_id = ?1", [download_id])?;
+    for (position, mirror) in mirrors.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO mirrors (download_id, position, url, priority, location)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            (
+                download_id,
+                position,
+                &mirror.url,
+                mirror.priority,
+                &mirror.location,
+            ),
+        )?;
+    }
+    tx.commit()
+}
+
+fn load_mirrors(conn: &Connection, download_id: i64) -> Result<Vec<Mirror>> {
+    let mut stmt = conn.prepare(
+        "SELECT url, priority, location FROM mirrors WHERE download_id = ?1 ORDER BY position",
+    )?;
+    let mirrors = stmt.query_map([download_id], |row| {
+        Ok(Mirror {
+            url: row.get(0)?,
+            priority: row.get(1)?,
+            location: row.get(2)?,
+        })
+    })?;
+    mirrors.collect()
+}
+
+fn save_credentials(
+    conn: &Connection,
+    download_id: i64,
+    credentials: Option<&Credentials>,
+) -> Result<()> {
+    match credentials {
+        Some(credentials) => conn.execute(
+            "INSERT INTO credentials (download_id, username, password) VALUES (?1, ?2, ?3)
+             ON CONFLICT(download_id) DO UPDATE SET username = ?2, password = ?3",
+            (download_id, &credentials.username, &credentials.password),
+        )?,
+        None => conn.execute(
+            "DELETE FROM credentials WHERE download_id = ?1",
+            [download_id],
+        )?,
+    };
+    Ok(())
+}
+
+fn load_credentials(conn: &Connection, download_id: i64) -> Result<Option<Credentials>> {
+    conn.query_row(
+        "SELECT username, password FROM credentials WHERE download_id = ?1",
+        [download_id],
+        |row| {
+            Ok(Credentials {
+                username: row.get(0)?,
+                password: row.get(1)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Keeps a login for every download from `host`, or forgets it if `None`.
+pub fn save_host_credentials(
+    conn: &Connection,
+    host: &str,
+    credentials: Option<&Credentials>,
+) -> Result<()> {
+    match credentials {
+        Some(credentials) => conn.execute(
+            "INSERT INTO credentials (host, username, password) VALUES (?1, ?2, ?3)
+             ON CONFLICT(host) DO UPDATE SET username = ?2, password = ?3",
+            (host, &credentials.username, &credentials.password),
+        )?,
+        None => conn.execute("DELETE FROM credentials WHERE host = ?1", [host])?,
+    };
+    Ok(())
+}
+
+pub fn load_host_credentials(conn: &Connection) -> Result<Vec<(String, Credentials)>> {
+    let mut stmt =
+        conn.prepare("SELECT host, username, password FROM credentials WHERE host IS NOT NULL")?;
+    let credentials = stmt.query_map([], |row| {
+        Ok((
+            row.get(0)?,
+            Credentials {
+                username: row.get(1)?,
+                password: row.get(2)?,
+            },
+        ))
+    })?;
+    credentials.collect()
+}
+
+fn save_chunks(conn: &Connection, download_id: i64, chunks: &[Chunk]) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM chunks WHERE download_id = ?1", [download_id])?;
+    for chunk in chunks {
+        tx.execute(
+            "INSERT INTO chunks (download_id, chunk_number, start_byte, end_byte, status, downloaded_bytes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            (
+                download_id,
+                chunk.chunk_number,
+                chunk.start_byte,
+                chunk.end_byte,
+                chunk.status.to_string(),
+                chunk.downloaded_bytes,
+            ),
+        )?;
+    }
+    tx.commit()
+}
+
+fn load_chunks(conn: &Connection, download_id: i64) -> Result<Vec<Chunk>> {
+    let mut stmt = conn.prepare(
+        "SELECT chunk_number, start_byte, end_byte, status, downloaded_bytes FROM chunks
+         WHERE download_id = ?1 ORDER BY chunk_number",
+    )?;
+
+    let chunks = stmt.query_map([download_id], |row| {
+        let status: String = row.get(3)?;
+        Ok(Chunk {
+            chunk_number: row.get(0)?,
+            start_byte: row.get(1)?,
+            end_byte: row.get(2)?,
+            status: ChunkStatus::parse(&status),
+            downloaded_bytes: row.get(4)?,
+        })
+    })?;
+
+    chunks.collect()
+}
+
 pub fn load_downloads(conn: &Connection) -> Result<Vec<DownloadItem>> {
     let mut stmt = conn.prepare(
-        "SELECT id, url, file_path, total_size, status, downloaded_bytes FROM downloads",
+        "SELECT id, url, file_path, total_size, status, downloaded_bytes, checksum, speed_limit, priority, collision_policy, download_dir, etag, last_modified, supports_ranges, file_name, pieces, headers, cookie, user_agent, referer, expected_size
+         FROM downloads ORDER BY priority, id",
     )?;
 
     let items = stmt.query_map([], |row| {
@@ -72,16 +450,28 @@ pub fn load_downloads(conn: &Connection) -> Result<Vec<DownloadItem>> {
         let downloaded_bytes: u64 = row.get(5)?;
         let total_size: Option<i64> = row.get(3)?;
 
+        let progress = if let Some(total) = total_size {
+            (downloaded_bytes as f32 / total as f32) * 100.0
+        } else {
+            0.0
+        };
+
         let status = match status_str.as_str() {
             "InProgress" | "Pending" => DownloadStatus::InProgress {
-                progress: if let Some(total) = total_size {
-                    (downloaded_bytes as f32 / total as f32) * 100.0
-                } else {
-                    0.0
-                },
+                progress,
+                downloaded_bytes,
+            },
+            "Paused" => DownloadStatus::Paused {
+                progress,
                 downloaded_bytes,
             },
+            "Queued" => DownloadStatus::Queued,
+            "Verifying" => DownloadStatus::Verifying,
             "Completed" => DownloadStatus::Completed,
+            "Skipped" => DownloadStatus::Skipped,
+            s if s.starts_with("Checksum mismatch: ") => {
+                DownloadStatus::ChecksumMismatch(s[19..].to_string())
+            }
             "Cancelled" => DownloadStatus::Cancelled,
             s if s.starts_with("Failed: ") => DownloadStatus::Failed(s[8..].to_string()),
             _ => DownloadStatus::Pending,
@@ -94,14 +484,143 @@ pub fn load_downloads(conn: &Connection) -> Result<Vec<DownloadItem>> {
         println!("Total size: {:?}", total_size);
         println!("Downloaded bytes: {}", downloaded_bytes);
 
+        let speed_limit: Option<u64> = row.get(7)?;
+
         Ok(DownloadItem {
             id: row.get(0)?,
             url: row.get(1)?,
             file_path: row.get(2)?,
             total_size,
             status,
+            chunks: Vec::new(),
+            checksum: row
+                .get::<_, Option<String>>(6)?
+                .as_deref()
+                .and_then(Checksum::parse),
+            speed_limit,
+            throttle: Arc::new(TokenBucket::new(speed_limit)),
+            priority: row.get(8)?,
+            collision_policy: row
+                .get::<_, Option<String>>(9)?
+                .as_deref()
+                .and_then(CollisionPolicy::parse),
+            download_dir: row.get(10)?,
+            validators: Validators {
+                etag: row.get(11)?,
+                last_modified: row.get(12)?,
+            },
+            supports_ranges: row.get(13)?,
+            file_name: row.get(14)?,
+            pieces: row
+                .get::<_, Option<String>>(15)?
+                .as_deref()
+                .and_then(PieceHashes::parse),
+            headers: RequestHeaders {
+                headers: row
+                    .get::<_, Option<String>>(16)?
+                    .as_deref()
+                    .map(RequestHeaders::parse_headers)
+                    .unwrap_or_default(),
+                cookie: row.get(17)?,
+                user_agent: row.get(18)?,
+                referer: row.get(19)?,
+                credentials: None,
+            },
+            expected_size: row.get(20)?,
+            ..DownloadItem::default()
         })
     })?;
 
-    items.collect()
+    items
+        .map(|item| {
+            let mut item = item?;
+            item.chunks = load_chunks(conn, item.id)?;
+            item.mirrors = load_mirrors(conn, item.id)?;
+            item.headers.credentials = load_credentials(conn, item.id)?;
+            Ok(item)
+        })
+        .collect()
+}
+
+pub fn save_torrent(conn: &Connection, item: &TorrentItem) -> Result<()> {
+    let status_str = match &item.status {
+        TorrentStatus::Checking { .. } | TorrentStatus::Downloading { .. } => {
+            "Downloading".to_string()
+        }
+        status => status.to_string(),
+    };
+
+    conn.execute(
+        "INSERT OR REPLACE INTO torrents (id, info_hash, name, total_size, metainfo, download_dir, status, downloaded_bytes, uploaded_bytes, priority)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            item.id,
+            item.metainfo.info_hash.to_string(),
+            &item.metainfo.name,
+            item.metainfo.total_length(),
+            &item.metainfo.bytes,
+            &item.download_dir,
+            status_str,
+            item.downloaded_bytes,
+            item.uploaded_bytes,
+            item.priority,
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn delete_torrent(conn: &Connection, id: i64) -> Result<()> {
+    conn.execute("DELETE FROM torrents WHERE id = ?1", [id])?;
+    Ok(())
+}
+
+/// Torrents whose saved `.torrent` no longer parses are left out and logged.
+pub fn load_torrents(conn: &Connection) -> Result<Vec<TorrentItem>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, metainfo, download_dir, status, downloaded_bytes, uploaded_bytes, priority
+         FROM torrents ORDER BY priority, id",
+    )?;
+
+    let rows = stmt.query_map([], |row| {
+        let id: i64 = row.get(0)?;
+        let bytes: Vec<u8> = row.get(1)?;
+        let status_str: String = row.get(3)?;
+        let downloaded_bytes: u64 = row.get(4)?;
+
+        let metainfo = match Metainfo::parse(&bytes) {
+            Ok(metainfo) => metainfo,
+            Err(e) => {
+                log::error!("Skipping saved torrent {}: {}", id, e);
+                return Ok(None);
+            }
+        };
+        let progress = match metainfo.total_length() {
+            0 => 0.0,
+            total => (downloaded_bytes as f32 / total as f32) * 100.0,
+        };
+
+        let status = match status_str.as_str() {
+            "Downloading" => TorrentStatus::Downloading { progress },
+            "Queued" => TorrentStatus::Queued,
+            "Completed" => TorrentStatus::Completed,
+            s if s.starts_with("Failed: ") => TorrentStatus::Failed(s[8..].to_string()),
+            _ => TorrentStatus::Stopped,
+        };
+
+        Ok(Some(TorrentItem {
+            id,
+            metainfo: Arc::new(metainfo),
+            status,
+            download_dir: row.get(2)?,
+            priority: row.get(6)?,
+            downloaded_bytes,
+            uploaded_bytes: row.get(5)?,
+            tracker: Default::default(),
+            peers: 0,
+            notice: None,
+            stop: Default::default(),
+        }))
+    })?;
+
+    rows.filter_map(Result::transpose).collect()
 }
@@ -0,0 +1,460 @@
+use base64::Engine;
+use md5::Md5;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::{Method, RequestBuilder, Response, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock, RwLock};
+
+/// A user name and password for one server.
+#[derive(Clone, PartialEq, Default)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+// Keeps passwords out of logs.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What a `401` asked for, and what answering it needs.
+#[derive(Debug, Clone)]
+enum Challenge {
+    Basic,
+    Digest {
+        realm: String,
+        nonce: String,
+        opaque: Option<String>,
+        /// Whether `qop=auth` was offered; RFC 2069 servers leave it out.
+        qop: bool,
+        algorithm: DigestAlgorithm,
+        /// The nonce ran out rather than the login being wrong.
+        stale: bool,
+        /// Requests made with this nonce so far, sent as `nc`.
+        count: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DigestAlgorithm {
+    Md5,
+    Md5Sess,
+    Sha256,
+    Sha256Sess,
+}
+
+impl DigestAlgorithm {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "MD5" => Some(DigestAlgorithm::Md5),
+            "MD5-SESS" => Some(DigestAlgorithm::Md5Sess),
+            "SHA-256" => Some(DigestAlgorithm::Sha256),
+            "SHA-256-SESS" => Some(DigestAlgorithm::Sha256Sess),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Md5 => "MD5",
+            DigestAlgorithm::Md5Sess => "MD5-sess",
+            DigestAlgorithm::Sha256 => "SHA-256",
+            DigestAlgorithm::Sha256Sess => "SHA-256-sess",
+        }
+    }
+
+    fn hash(self, data: &str) -> String {
+        let bytes = match self {
+            DigestAlgorithm::Md5 | DigestAlgorithm::Md5Sess => Md5::digest(data).to_vec(),
+            DigestAlgorithm::Sha256 | DigestAlgorithm::Sha256Sess => Sha256::digest(data).to_vec(),
+        };
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl Challenge {
+    /// The strongest challenge in a response that we know how to answer.
+    fn from_headers(headers: &HeaderMap) -> Option<Challenge> {
+        let challenges: Vec<(String, HashMap<String, String>)> = headers
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(parse_challenges)
+            .collect();
+
+        let digests = challenges
+            .iter()
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("digest"))
+            .filter_map(|(_, params)| Challenge::digest(params));
+        let best_digest = digests.max_by_key(|challenge| match challenge {
+            Challenge::Digest { algorithm, .. } => matches!(
+                algorithm,
+                DigestAlgorithm::Sha256 | DigestAlgorithm::Sha256Sess
+            ),
+            Challenge::Basic => false,
+        });
+
+        best_digest.or_else(|| {
+            challenges
+                .iter()
+                .any(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+                .then_some(Challenge::Basic)
+        })
+    }
+
+    fn digest(params: &HashMap<String, String>) -> Option<Challenge> {
+        let qop = match params.get("qop") {
+            Some(qop) => qop
+                .split(',')
+                .any(|option| option.trim().eq_ignore_ascii_case("auth")),
+            None => false,
+        };
+        // Only `auth-int` on offer, which would mean hashing the body.
+        if params.contains_key("qop") && !qop {
+            return None;
+        }
+        let algorithm = match params.get("algorithm") {
+            Some(name) => DigestAlgorithm::parse(name)?,
+            None => DigestAlgorithm::Md5,
+        };
+        Some(Challenge::Digest {
+            realm: params.get("realm").cloned().unwrap_or_default(),
+            nonce: params.get("nonce")?.clone(),
+            opaque: params.get("opaque").cloned(),
+            qop,
+            algorithm,
+            stale: params
+                .get("stale")
+                .is_some_and(|stale| stale.eq_ignore_ascii_case("true")),
+            count: 0,
+        })
+    }
+
+    fn same_realm(&self, other: &Challenge) -> bool {
+        match (self, other) {
+            (Challenge::Basic, Challenge::Basic) => true,
+            (Challenge::Digest { realm, .. }, Challenge::Digest { realm: other, .. }) => {
+                realm == other
+            }
+            _ => false,
+        }
+    }
+
+    fn is_stale(&self) -> bool {
+        matches!(self, Challenge::Digest { stale: true, .. })
+    }
+
+    /// The `Authorization` value for one request.
+    fn answer(&mut self, credentials: &Credentials, method: &Method, url: &Url) -> String {
+        match self {
+            Challenge::Basic => format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD
+                    .encode(format!("{}:{}", credentials.username, credentials.password))
+            ),
+            Challenge::Digest {
+                realm,
+                nonce,
+                opaque,
+                qop,
+                algorithm,
+                count,
+                ..
+            } => {
+                *count += 1;
+                let nc = format!("{:08x}", count);
+                let cnonce = format!("{:016x}", rand::random::<u64>());
+                let uri = match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_string(),
+                };
+
+                let mut ha1 = algorithm.hash(&format!(
+                    "{}:{}:{}",
+                    credentials.username, realm, credentials.password
+                ));
+                if matches!(
+                    algorithm,
+                    DigestAlgorithm::Md5Sess | DigestAlgorithm::Sha256Sess
+                ) {
+                    ha1 = algorithm.hash(&format!("{}:{}:{}", ha1, nonce, cnonce));
+                }
+                let ha2 = algorithm.hash(&format!("{}:{}", method, uri));
+                let response = if *qop {
+                    algorithm.hash(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2))
+                } else {
+                    algorithm.hash(&format!("{}:{}:{}", ha1, nonce, ha2))
+                };
+
+                let mut value = format!(
+                    "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, response=\"{}\"",
+                    quote(&credentials.username),
+                    quote(realm),
+                    quote(nonce),
+                    quote(&uri),
+                    algorithm.name(),
+                    response
+                );
+                if *qop {
+                    value.push_str(&format!(", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce));
+                }
+                if let Some(opaque) = opaque {
+                    value.push_str(&format!(", opaque=\"{}\"", quote(opaque)));
+                }
+                value
+            }
+        }
+    }
+}
+
+/// Splits a `WWW-Authenticate` value into its challenges, each a scheme
+/// and its parameters. One header can hold several.
+fn parse_challenges(value: &str) -> Vec<(String, HashMap<String, String>)> {
+    let mut challenges: Vec<(String, HashMap<String, String>)> = Vec::new();
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let end = rest.find([' ', ',', '=']).unwrap_or(rest.len());
+        let (token, after) = (&rest[..end], rest[end..].trim_start());
+        if token.is_empty() {
+            // A stray separator.
+            rest = rest[1..].trim_start();
+            continue;
+        }
+
+        let param = after.strip_prefix('=').filter(|_| !challenges.is_empty());
+        match param {
+            Some(after) => {
+                let after = after.trim_start();
+                let (value, remaining) = match after.strip_prefix('"') {
+                    Some(quoted) => unquote(quoted),
+                    None => {
+                        let end = after.find(',').unwrap_or(after.len());
+                        (after[..end].trim().to_string(), &after[end..])
+                    }
+                };
+                if let Some((_, params)) = challenges.last_mut() {
+                    params.insert(token.to_ascii_lowercase(), value);
+                }
+                rest = remaining.trim_start();
+            }
+            None => {
+                challenges.push((token.to_string(), HashMap::new()));
+                rest = after;
+            }
+        }
+    }
+    challenges
+}
+
+/// Reads a quoted string up to its closing quote, returning it and what follows.
+fn unquote(quoted: &str) -> (String, &str) {
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return (value, &quoted[index + 1..]),
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    value.push(escaped);
+                }
+            }
+            c => value.push(c),
+        }
+    }
+    (value, "")
+}
+
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The realm a `401` names, to tell the user what they are logging in to.
+pub fn realm(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(parse_challenges)
+        .find_map(|(_, mut params)| params.remove("realm"))
+        .filter(|realm| !realm.is_empty())
+}
+
+/// The last challenge from each server, so later requests answer it up
+/// front instead of being turned away first.
+fn challenges() -> &'static Mutex<HashMap<String, Challenge>> {
+    static CHALLENGES: OnceLock<Mutex<HashMap<String, Challenge>>> = OnceLock::new();
+    CHALLENGES.get_or_init(Default::default)
+}
+
+/// Logins kept for whole hosts rather than one download.
+fn saved() -> &'static RwLock<HashMap<String, Credentials>> {
+    static SAVED: OnceLock<RwLock<HashMap<String, Credentials>>> = OnceLock::new();
+    SAVED.get_or_init(Default::default)
+}
+
+/// Uses `credentials` for every download from `host`, or stops if `None`.
+pub fn remember(host: &str, credentials: Option<Credentials>) {
+    let mut saved = saved().write().unwrap();
+    match credentials {
+        Some(credentials) => saved.insert(host.to_ascii_lowercase(), credentials),
+        None => saved.remove(&host.to_ascii_lowercase()),
+    };
+}
+
+pub fn host_of(url: &str) -> Option<String> {
+    Url::parse(url)
+        .ok()?
+        .host_str()
+        .map(str::to_ascii_lowercase)
+}
+
+/// Who to log in to `url` as: the download's own login on the host it was
+/// given for, then one remembered for the host, then `~/.netrc`.
+pub fn credentials_for(url: &Url, origin: &str, own: Option<&Credentials>) -> Option<Credentials> {
+    let host = url.host_str()?.to_ascii_lowercase();
+    if let Some(own) = own.filter(|_| host_of(origin).as_deref() == Some(host.as_str())) {
+        return Some(own.clone());
+    }
+    if let Some(saved) = saved().read().unwrap().get(&host) {
+        return Some(saved.clone());
+    }
+    netrc(&host)
+}
+
+/// Sends `request`, answering a Basic or Digest challenge if the server
+/// asks to log in and there's a login for it. A server already known to ask
+/// gets the answer with the first attempt.
+pub async fn send(
+    request: RequestBuilder,
+    origin: &str,
+    own: Option<&Credentials>,
+) -> reqwest::Result<Response> {
+    let (client, request) = request.build_split();
+    let mut request = request?;
+    let url = request.url().clone();
+    let key = format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default().to_ascii_lowercase(),
+        url.port_or_known_default().unwrap_or_default()
+    );
+
+    let mut answered = None;
+    if challenges().lock().unwrap().contains_key(&key) {
+        if let Some(credentials) = credentials_for(&url, origin, own) {
+            answered = authorize(&mut request, &key, &credentials, None);
+        }
+    }
+
+    let retry = request.try_clone();
+    let response = client.execute(request).await?;
+    if response.status() != StatusCode::UNAUTHORIZED {
+        return Ok(response);
+    }
+    let (Some(mut retry), Some(challenge)) = (retry, Challenge::from_headers(response.headers()))
+    else {
+        return Ok(response);
+    };
+    // Turned away despite answering: the login is wrong, unless the nonce
+    // merely expired or this part of the server asks for something else.
+    if answered.is_some_and(|answered| answered.same_realm(&challenge)) && !challenge.is_stale() {
+        return Ok(response);
+    }
+    let Some(credentials) = credentials_for(&url, origin, own) else {
+        return Ok(response);
+    };
+
+    authorize(&mut retry, &key, &credentials, Some(challenge));
+    client.execute(retry).await
+}
+
+/// Adds the answer to the server's challenge, storing `challenge` first
+/// if given. Returns the challenge answered, if there was one.
+fn authorize(
+    request: &mut reqwest::Request,
+    key: &str,
+    credentials: &Credentials,
+    challenge: Option<Challenge>,
+) -> Option<Challenge> {
+    let mut challenges = challenges().lock().unwrap();
+    if let Some(challenge) = challenge {
+        challenges.insert(key.to_string(), challenge);
+    }
+    let challenge = challenges.get_mut(key)?;
+    let answer = challenge.answer(credentials, request.method(), request.url());
+    let value = HeaderValue::from_str(&answer).ok()?;
+    request.headers_mut().insert(AUTHORIZATION, value);
+    Some(challenge.clone())
+}
+
+/// The login `~/.netrc` (or `$NETRC`) has for `host`, falling back to its
+/// `default` entry.
+fn netrc(host: &str) -> Option<Credentials> {
+    let path = std::env::var("NETRC")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or_else(|| crate::settings::expand_home("~/.netrc"));
+    let text = std::fs::read_to_string(path).ok()?;
+    parse_netrc(&text, host)
+}
+
+fn parse_netrc(text: &str, host: &str) -> Option<Credentials> {
+    // Macro definitions run to the next blank line and hold no logins.
+    let mut tokens = Vec::new();
+    let mut in_macro = false;
+    for line in text.lines() {
+        if in_macro {
+            in_macro = !line.trim().is_empty();
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        while let Some(word) = words.next() {
+            if word == "macdef" {
+                words.next();
+                in_macro = true;
+                break;
+            }
+            tokens.push(word);
+        }
+    }
+
+    // Entries as (machine, login, password), with `None` for `default`.
+    let mut entries: Vec<(Option<&str>, Option<&str>, Option<&str>)> = Vec::new();
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => entries.push((Some(tokens.next()?), None, None)),
+            "default" => entries.push((None, None, None)),
+            "login" => {
+                let login = tokens.next();
+                if let Some(entry) = entries.last_mut() {
+                    entry.1 = login;
+                }
+            }
+            "password" => {
+                let password = tokens.next();
+                if let Some(entry) = entries.last_mut() {
+                    entry.2 = password;
+                }
+            }
+            "account" => {
+                tokens.next();
+            }
+            _ => {}
+        }
+    }
+
+    let entry = entries
+        .iter()
+        .find(|(machine, ..)| machine.is_some_and(|machine| machine.eq_ignore_ascii_case(host)))
+        .or_else(|| entries.iter().find(|(machine, ..)| machine.is_none()))?;
+    Some(Credentials {
+        username: entry.1.unwrap_or_default().to_string(),
+        password: entry.2.unwrap_or_default().to_string(),
+    })
+}
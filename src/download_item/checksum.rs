@@ -0,0 +1,236 @@
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Display};
+use std::io::{Read, Seek, SeekFrom};
+
+use super::headers::RequestHeaders;
+use crate::utils::http;
+
+/// Sidecar files are a line or a few hundred; anything bigger isn't one.
+const MAX_SIDECAR_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha1,
+    Md5,
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+            HashAlgorithm::Sha1 => write!(f, "sha1"),
+            HashAlgorithm::Md5 => write!(f, "md5"),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    /// Strongest first, for picking between digests of the same file.
+    pub const BY_STRENGTH: [HashAlgorithm; 3] = [
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Sha1,
+        HashAlgorithm::Md5,
+    ];
+
+    /// Takes `sha256`, `SHA-256` and the like.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "md5" => Some(HashAlgorithm::Md5),
+            _ => None,
+        }
+    }
+
+    /// Bare hex digests are told apart by their length.
+    fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            64 => Some(HashAlgorithm::Sha256),
+            40 => Some(HashAlgorithm::Sha1),
+            32 => Some(HashAlgorithm::Md5),
+            _ => None,
+        }
+    }
+}
+
+/// An expected digest, written as `sha256:<hex>` or just the hex.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    pub hex: String,
+}
+
+impl Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.hex)
+    }
+}
+
+impl Checksum {
+    pub fn parse(input: &str) -> Option<Self> {
+        let input = input.trim();
+        let (algorithm, hex) = match input.split_once(':') {
+            Some((name, hex)) => (HashAlgorithm::from_name(name)?, hex.trim()),
+            None => (HashAlgorithm::from_hex_len(input.len())?, input),
+        };
+
+        let valid = HashAlgorithm::from_hex_len(hex.len()) == Some(algorithm)
+            && hex.chars().all(|c| c.is_ascii_hexdigit());
+        valid.then(|| Checksum {
+            algorithm,
+            hex: hex.to_ascii_lowercase(),
+        })
+    }
+
+    pub fn matches(&self, actual: &str) -> bool {
+        self.hex.eq_ignore_ascii_case(actual)
+    }
+}
+
+/// Digests of consecutive fixed-size pieces of a file, so a bad stretch can
+/// be caught, and fetched again, before the whole file is in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PieceHashes {
+    pub algorithm: HashAlgorithm,
+    /// Every piece is this long except possibly the last.
+    pub length: u64,
+    pub hashes: Vec<String>,
+}
+
+impl Display for PieceHashes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.algorithm,
+            self.length,
+            self.hashes.join(",")
+        )
+    }
+}
+
+impl PieceHashes {
+    /// Reads the `algorithm:length:hex,hex,...` form it's stored in.
+    pub fn parse(input: &str) -> Option<Self> {
+        let mut parts = input.trim().splitn(3, ':');
+        let algorithm = HashAlgorithm::from_name(parts.next()?)?;
+        let length = parts.next()?.parse::<u64>().ok().filter(|len| *len > 0)?;
+        let hashes = parts
+            .next()?
+            .split(',')
+            .map(|hex| Checksum::parse(&format!("{}:{}", algorithm, hex)).map(|c| c.hex))
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            algorithm,
+            length,
+            hashes,
+        })
+    }
+
+    /// Byte range of piece `index`, with the end exclusive and clamped to
+    /// `total_size`.
+    pub fn range(&self, index: usize, total_size: u64) -> (u64, u64) {
+        let start = index as u64 * self.length;
+        (start, (start + self.length).min(total_size))
+    }
+}
+
+/// Hashes a file on disk. This reads the whole file, so run it off the
+/// async executor.
+pub fn hash_file(path: &str, algorithm: HashAlgorithm) -> std::io::Result<String> {
+    let file = std::fs::File::open(path)?;
+    match algorithm {
+        HashAlgorithm::Sha256 => digest_reader::<Sha256>(file),
+        HashAlgorithm::Sha1 => digest_reader::<Sha1>(file),
+        HashAlgorithm::Md5 => digest_reader::<Md5>(file),
+    }
+}
+
+/// Hashes `len` bytes of a file starting at `offset`. Blocks like `hash_file`.
+pub fn hash_range(
+    path: &str,
+    offset: u64,
+    len: u64,
+    algorithm: HashAlgorithm,
+) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let range = file.take(len);
+    match algorithm {
+        HashAlgorithm::Sha256 => digest_reader::<Sha256>(range),
+        HashAlgorithm::Sha1 => digest_reader::<Sha1>(range),
+        HashAlgorithm::Md5 => digest_reader::<Md5>(range),
+    }
+}
+
+fn digest_reader<D: Digest>(mut reader: impl Read) -> std::io::Result<String> {
+    let mut hasher = D::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Looks for a published digest next to the download: `<url>.sha256` first,
+/// then a `SHA256SUMS` listing in the same directory.
+pub async fn find_sidecar(
+    url: &str,
+    headers: &RequestHeaders,
+    file_name: &str,
+) -> Option<Checksum> {
+    if let Some(body) = fetch_sidecar(&format!("{}.sha256", url), url, headers).await {
+        if let Some(checksum) = parse_sums(&body, None) {
+            return Some(checksum);
+        }
+    }
+
+    let base = url.split(['?', '#']).next()?;
+    let directory = &base[..base.rfind('/')? + 1];
+    let body = fetch_sidecar(&format!("{}SHA256SUMS", directory), url, headers).await?;
+    parse_sums(&body, Some(file_name))
+}
+
+async fn fetch_sidecar(url: &str, origin: &str, headers: &RequestHeaders) -> Option<String> {
+    let response = headers
+        .send(http::client().get(url), url, origin)
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?;
+    if response
+        .content_length()
+        .is_some_and(|len| len > MAX_SIDECAR_SIZE as u64)
+    {
+        return None;
+    }
+    let bytes = response.bytes().await.ok()?;
+    (bytes.len() <= MAX_SIDECAR_SIZE).then(|| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Reads `sha256sum` output. With a file name only its line counts,
+/// otherwise the first digest found does.
+fn parse_sums(body: &str, file_name: Option<&str>) -> Option<Checksum> {
+    body.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let hex = parts.next()?;
+        // `*` marks files hashed in binary mode.
+        let name = parts.next().map(|name| name.trim_start_matches('*'));
+        match (file_name, name) {
+            (Some(wanted), Some(name)) if name.rsplit('/').next() != Some(wanted) => None,
+            (Some(_), None) => None,
+            _ => Checksum::parse(&format!("sha256:{}", hex)),
+        }
+    })
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use super::naming;
+
+/// What to do when a download's file name is already taken, on disk or by
+/// another download in the list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CollisionPolicy {
+    /// Save as `file (1).zip`, `file (2).zip`, ...
+    #[default]
+    Rename,
+    Overwrite,
+    /// Leave the existing file alone and don't download at all.
+    Skip,
+    /// Treat an existing file, or a partial one left behind, as long as it
+    /// is no bigger than the remote one, as a head start and continue from
+    /// its end.
+    Resume,
+}
+
+impl CollisionPolicy {
+    pub const ALL: [CollisionPolicy; 4] = [
+        CollisionPolicy::Rename,
+        CollisionPolicy::Overwrite,
+        CollisionPolicy::Skip,
+        CollisionPolicy::Resume,
+    ];
+
+    /// The form stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CollisionPolicy::Rename => "rename",
+            CollisionPolicy::Overwrite => "overwrite",
+            CollisionPolicy::Skip => "skip",
+            CollisionPolicy::Resume => "resume",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|policy| policy.as_str() == value)
+    }
+}
+
+impl Display for CollisionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CollisionPolicy::Rename => write!(f, "Rename"),
+            CollisionPolicy::Overwrite => write!(f, "Overwrite"),
+            CollisionPolicy::Skip => write!(f, "Skip"),
+            CollisionPolicy::Resume => write!(f, "Resume existing"),
+        }
+    }
+}
+
+/// Where a download ended up after its name was checked for collisions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Claim {
+    /// Start from scratch at this path.
+    New(String),
+    /// Carry on from the end of the file already at this path.
+    Existing(String, u64),
+    /// The name is taken and the policy says not to download.
+    Skip(String),
+}
+
+/// Paths held by downloads in the list, so two of them can't pick the same
+/// name before either has created its file.
+fn reservations() -> &'static Mutex<HashMap<String, i64>> {
+    static RESERVATIONS: OnceLock<Mutex<HashMap<String, i64>>> = OnceLock::new();
+    RESERVATIONS.get_or_init(Mutex::default)
+}
+
+/// Holds `path` for download `id`. Returns `false` if another download
+/// already has it.
+pub fn reserve(id: i64, path: &str) -> bool {
+    let mut reservations = reservations().lock().unwrap();
+    match reservations.get(path) {
+        Some(owner) if *owner != id => false,
+        _ => {
+            reservations.retain(|_, owner| *owner != id);
+            reservations.insert(path.to_string(), id);
+            true
+        }
+    }
+}
+
+pub fn release(id: i64) {
+    reservations()
+        .lock()
+        .unwrap()
+        .retain(|_, owner| *owner != id);
+}
+
+/// Picks the path download `id` should write `file_name` to inside `dir`
+/// and reserves it. `total_size` and `supports_ranges` decide whether an
+/// existing file can be resumed.
+pub fn claim(
+    id: i64,
+    dir: &str,
+    file_name: &str,
+    policy: CollisionPolicy,
+    total_size: Option<u64>,
+    supports_ranges: bool,
+) -> Claim {
+    let mut reservations = reservations().lock().unwrap();
+    let held_by_other = |path: &str| reservations.get(path).is_some_and(|owner| *owner != id);
+
+    let path = format!("{}/{}", dir, file_name);
+    let len = |path: &str| std::fs::metadata(path).ok().map(|metadata| metadata.len());
+    let on_disk = len(&naming::part_path(&path)).or_else(|| len(&path));
+
+    let claim = match (policy, on_disk) {
+        // Our own file from an earlier attempt, which is being started over.
+        _ if reservations.get(&path) == Some(&id) => Claim::New(path),
+        _ if held_by_other(&path) => match policy {
+            CollisionPolicy::Skip => Claim::Skip(path),
+            // Never write into a file another download is still filling.
+            _ => Claim::New(free_path(dir, file_name, held_by_other)),
+        },
+        (_, None) | (CollisionPolicy::Overwrite, _) => Claim::New(path),
+        (CollisionPolicy::Skip, Some(_)) => Claim::Skip(path),
+        (CollisionPolicy::Resume, Some(len))
+            if supports_ranges && total_size.is_some_and(|total| len <= total) =>
+        {
+            Claim::Existing(path, len)
+        }
+        (CollisionPolicy::Rename | CollisionPolicy::Resume, Some(_)) => {
+            Claim::New(free_path(dir, file_name, held_by_other))
+        }
+    };
+
+    if let Claim::New(path) | Claim::Existing(path, _) = &claim {
+        reservations.retain(|_, owner| *owner != id);
+        reservations.insert(path.clone(), id);
+    }
+    claim
+}
+
+/// The first of `file (1).zip`, `file (2).zip`, ... that is neither on disk
+/// nor reserved.
+fn free_path(dir: &str, file_name: &str, held_by_other: impl Fn(&str) -> bool) -> String {
+    let (stem, extension) = split_extension(file_name);
+    (1..)
+        .map(|n| format!("{}/{} ({}){}", dir, stem, n, extension))
+        .find(|path| !held_by_other(path) && !exists(path))
+        .unwrap()
+}
+
+/// Whether the file, or a partial download of it, is on disk.
+fn exists(path: &str) -> bool {
+    Path::new(path).exists() || Path::new(&naming::part_path(path)).exists()
+}
+
+/// Splits off the extension, keeping `.tar.gz` and friends together.
+fn split_extension(file_name: &str) -> (&str, &str) {
+    if let Some(index) = file_name.to_ascii_lowercase().rfind(".tar.") {
+        if index > 0 {
+            return file_name.split_at(index);
+        }
+    }
+    match file_name.rfind('.') {
+        Some(index) if index > 0 => file_name.split_at(index),
+        _ => (file_name, ""),
+    }
+}
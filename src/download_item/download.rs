@@ -0,0 +1,1376 @@
+use std::fmt;
+use std::io::SeekFrom;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use super::auth;
+use super::checksum::{self, Checksum, PieceHashes};
+use super::collision::{self, Claim, CollisionPolicy};
+use super::ftp;
+use super::headers::RequestHeaders;
+use super::mirrors::{self, Source, Sources};
+use super::naming;
+use super::retry::{parse_retry_after, RetryPolicy};
+use super::throttle::{self, TokenBucket};
+use super::{format_bytes, DownloadItem};
+use crate::settings::Settings;
+use crate::utils::http;
+
+/// Upper bound on the number of parallel connections used for one download.
+const MAX_SEGMENTS: u64 = 8;
+/// Ranges smaller than this aren't worth opening another connection for.
+const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+/// Where downloads went before the folder was configurable.
+const LEGACY_DOWNLOAD_DIR: &str = "downloads";
+/// A file that keeps changing under us isn't worth chasing forever.
+const MAX_RESTARTS: u32 = 3;
+/// How often progress (and chunk state) is reported back to the app.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone)]
+pub enum Progress {
+    /// The transfer is set up: where it is being saved, how big it is and
+    /// how it has been split.
+    Started {
+        file_path: String,
+        total_size: Option<u64>,
+        chunks: Vec<Chunk>,
+        validators: Validators,
+        supports_ranges: Option<bool>,
+    },
+    Advanced(f32, u64, Vec<Chunk>),
+    Paused(f32, u64, Vec<Chunk>),
+    /// A connection dropped and is being retried: attempt, out of how many.
+    Retrying(u32, u32),
+    /// Every byte is on disk and the file is being hashed.
+    Verifying,
+    Finished,
+    /// The file already exists and the collision policy said to leave it.
+    Skipped(String),
+    /// The file on the server is no longer the one being resumed, so the
+    /// download starts over. Sizes are before and after, where known.
+    Changed {
+        old_size: Option<u64>,
+        new_size: Option<u64>,
+    },
+    /// The server can't resume, so the download started over from zero.
+    Restarted,
+}
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    Download(String),
+    Network(String),
+    /// Unsuccessful HTTP status, with the server's `Retry-After` if it sent one.
+    Status(reqwest::StatusCode, Option<Duration>),
+    /// A resume was answered with the whole file, which has changed since
+    /// the download began. Carries the new size if the server sent one.
+    Changed(Option<u64>),
+    /// The server ignores ranges, so a partial download can't be continued.
+    NotResumable,
+    /// `host` wants a login, or turned down the one it was given.
+    Unauthorized {
+        host: String,
+        realm: Option<String>,
+    },
+    /// A mirror stalled or fell below `mirrors::MIN_SPEED` while others
+    /// were available.
+    TooSlow,
+    /// Piece `n` didn't match its hash and is being fetched again.
+    PieceMismatch(usize),
+    ChecksumMismatch {
+        expected: Checksum,
+        actual: String,
+    },
+    /// Every URL serves a file of a different size from the one the
+    /// metalink gave.
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Download(msg) => write!(f, "Download error: {}", msg),
+            Error::Network(msg) => write!(f, "Network error: {}", msg),
+            Error::Status(status, _) => write!(f, "Server responded with {}", status),
+            Error::Changed(_) => write!(f, "File changed on the server"),
+            Error::NotResumable => write!(f, "Server doesn't support resume"),
+            Error::Unauthorized { host, .. } => write!(f, "Login needed for {}", host),
+            Error::TooSlow => write!(f, "Mirror too slow"),
+            Error::PieceMismatch(index) => write!(f, "Piece {} doesn't match its hash", index),
+            Error::SizeMismatch { expected, actual } => write!(
+                f,
+                "Size mismatch: expected {}, got {}",
+                format_bytes(*expected),
+                format_bytes(*actual)
+            ),
+            Error::ChecksumMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "Checksum mismatch: expected {}, got {}",
+                    expected, actual
+                )
+            }
+        }
+    }
+}
+
+impl Error {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Download(_)
+            | Error::Changed(_)
+            | Error::NotResumable
+            | Error::Unauthorized { .. }
+            | Error::ChecksumMismatch { .. }
+            | Error::SizeMismatch { .. } => false,
+            Error::Network(_) | Error::TooSlow | Error::PieceMismatch(_) => true,
+            Error::Status(status, _) => {
+                status.is_server_error()
+                    || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    || *status == reqwest::StatusCode::REQUEST_TIMEOUT
+            }
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::Status(_, retry_after) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Network(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Download(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ChunkStatus {
+    #[default]
+    Pending,
+    InProgress,
+    Completed,
+}
+
+impl fmt::Display for ChunkStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkStatus::Pending => write!(f, "Pending"),
+            ChunkStatus::InProgress => write!(f, "InProgress"),
+            ChunkStatus::Completed => write!(f, "Completed"),
+        }
+    }
+}
+
+impl ChunkStatus {
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "InProgress" => ChunkStatus::InProgress,
+            "Completed" => ChunkStatus::Completed,
+            _ => ChunkStatus::Pending,
+        }
+    }
+}
+
+/// A byte range of the output file fetched over its own connection.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Chunk {
+    pub chunk_number: u32,
+    pub start_byte: u64,
+    /// Inclusive end of the range, `None` when the server didn't report a size.
+    pub end_byte: Option<u64>,
+    pub downloaded_bytes: u64,
+    pub status: ChunkStatus,
+}
+
+impl Chunk {
+    /// Offset in the file where the next byte of this chunk goes.
+    pub fn position(&self) -> u64 {
+        self.start_byte + self.downloaded_bytes
+    }
+
+    fn remaining(&self) -> Option<u64> {
+        self.end_byte
+            .map(|end| (end + 1).saturating_sub(self.position()))
+    }
+
+    fn range_header(&self) -> Option<String> {
+        match self.end_byte {
+            Some(end) => Some(format!("bytes={}-{}", self.position(), end)),
+            None if self.position() > 0 => Some(format!("bytes={}-", self.position())),
+            None => None,
+        }
+    }
+}
+
+/// How the server identified the version of the file being downloaded, so
+/// a resume can tell whether it is still the same file.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn from_headers(headers: &reqwest::header::HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+        }
+    }
+
+    /// Weak ETags aren't allowed in `If-Range`, so those fall back to the date.
+    fn if_range(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+}
+
+/// Splits a file into ranges, one per connection. Without a known size or
+/// range support the whole file is a single open-ended chunk. Ranges start
+/// on a multiple of `piece_length`, if given, so each piece can be checked
+/// by the chunk holding it.
+pub fn plan_chunks(
+    total_size: Option<u64>,
+    supports_ranges: bool,
+    piece_length: Option<u64>,
+) -> Vec<Chunk> {
+    let total = match total_size {
+        Some(total) if supports_ranges && total > 0 => total,
+        _ => {
+            return vec![Chunk {
+                end_byte: total_size.filter(|total| *total > 0).map(|total| total - 1),
+                ..Chunk::default()
+            }]
+        }
+    };
+
+    let segments = (total / MIN_SEGMENT_SIZE).clamp(1, MAX_SEGMENTS);
+    let segment_size = match piece_length {
+        Some(length) => total.div_ceil(segments).next_multiple_of(length),
+        None => total.div_ceil(segments),
+    };
+
+    (0..segments)
+        .map(|i| i * segment_size)
+        .take_while(|start| *start < total)
+        .enumerate()
+        .map(|(i, start)| Chunk {
+            chunk_number: i as u32,
+            start_byte: start,
+            end_byte: Some((start + segment_size).min(total) - 1),
+            ..Chunk::default()
+        })
+        .collect()
+}
+
+/// Everything a transfer needs to start, or to pick up where it left off.
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: i64,
+    pub url: String,
+    /// Other URLs serving the same file.
+    pub mirrors: Vec<String>,
+    /// Name to save as, instead of the one the server suggests.
+    pub file_name: Option<String>,
+    /// Sent with every request for the file.
+    pub headers: RequestHeaders,
+    /// Where an earlier run saved the file; empty until the server is probed.
+    pub file_path: String,
+    pub total_size: Option<u64>,
+    /// The size a metalink gave; URLs serving any other size are passed over.
+    pub expected_size: Option<u64>,
+    pub chunks: Vec<Chunk>,
+    /// From the response that started the download, checked on resume.
+    pub validators: Validators,
+    /// Whether the server takes ranges, if that's been found out yet.
+    pub supports_ranges: Option<bool>,
+    pub checksum: Option<Checksum>,
+    /// Checked as each chunk completes.
+    pub pieces: Option<PieceHashes>,
+    /// Folder picked for this download; otherwise `settings` decide.
+    pub download_dir: Option<String>,
+    pub settings: Settings,
+    /// Applies when a fresh download's name turns out to be taken.
+    pub collision: CollisionPolicy,
+    pub stop: CancellationToken,
+    /// This download's own speed limit, applied on top of the global one.
+    pub throttle: Arc<TokenBucket>,
+}
+
+pub fn file(job: Job) -> iced::Subscription<(i64, Result<Progress, Error>)> {
+    iced::Subscription::run_with_id(
+        (
+            std::any::TypeId::of::<DownloadItem>(),
+            job.id,
+            job.url.clone(),
+        ),
+        create_download_stream(job),
+    )
+}
+
+enum SegmentEvent {
+    Advanced(u32, u64),
+    /// The worker is done with the chunk, either because it is `Completed`
+    /// or because it was asked to stop while still `InProgress`.
+    Finished(u32, ChunkStatus),
+    Retrying(u32),
+    /// Bad pieces were found and the chunk is back to this many bytes.
+    Rewound(u32, u64),
+    Failed(Error),
+}
+
+/// Segment tasks are aborted when the subscription stream is dropped.
+struct Workers(Vec<JoinHandle<()>>);
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.0.iter().for_each(JoinHandle::abort);
+    }
+}
+
+struct Transfer {
+    id: i64,
+    url: String,
+    file_path: String,
+    /// The name the server gave the file, which checksum lists go by.
+    remote_name: String,
+    checksum: Option<Checksum>,
+    total_size: Option<u64>,
+    chunks: Vec<Chunk>,
+    validators: Validators,
+    supports_ranges: Option<bool>,
+    receiver: mpsc::UnboundedReceiver<SegmentEvent>,
+    stop: CancellationToken,
+    retry: RetryPolicy,
+    /// Starts the download over if the file changes on the server.
+    restart: Job,
+    restarts: u32,
+    _workers: Workers,
+}
+
+impl Transfer {
+    fn downloaded(&self) -> u64 {
+        self.chunks.iter().map(|chunk| chunk.downloaded_bytes).sum()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.chunks
+            .iter()
+            .all(|chunk| chunk.status == ChunkStatus::Completed)
+    }
+
+    fn chunk_mut(&mut self, chunk_number: u32) -> Option<&mut Chunk> {
+        self.chunks
+            .iter_mut()
+            .find(|chunk| chunk.chunk_number == chunk_number)
+    }
+
+    fn percent(&self) -> f32 {
+        match self.total_size {
+            Some(total) if total > 0 => (self.downloaded() as f32 / total as f32) * 100.0,
+            _ => 0.0,
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        Progress::Advanced(self.percent(), self.downloaded(), self.chunks.clone())
+    }
+
+    fn paused(&self) -> Progress {
+        Progress::Paused(self.percent(), self.downloaded(), self.chunks.clone())
+    }
+
+    /// Once the last chunk is in, the file still has to pass verification.
+    fn into_verifying(self) -> State {
+        State::Verifying {
+            id: self.id,
+            url: self.url,
+            headers: Box::new(self.restart.headers),
+            file_path: self.file_path,
+            remote_name: self.remote_name,
+            checksum: self.checksum,
+        }
+    }
+}
+
+enum State {
+    /// About to start, counting how often the file changed on the server.
+    Ready(Box<Job>, u32),
+    Downloading(Box<Transfer>),
+    Verifying {
+        id: i64,
+        url: String,
+        headers: Box<RequestHeaders>,
+        file_path: String,
+        remote_name: String,
+        checksum: Option<Checksum>,
+    },
+    Finished,
+}
+
+fn create_download_stream(job: Job) -> impl futures::Stream<Item = (i64, Result<Progress, Error>)> {
+    futures::stream::unfold(State::Ready(Box::new(job), 0), |state| async move {
+        match state {
+            State::Ready(job, restarts) => {
+                let id = job.id;
+                match start_transfer(*job, restarts).await {
+                    Ok(Setup::Skipped(file_path)) => {
+                        Some(((id, Ok(Progress::Skipped(file_path))), State::Finished))
+                    }
+                    Ok(Setup::Transfer(transfer)) => Some((
+                        (
+                            id,
+                            Ok(Progress::Started {
+                                file_path: transfer.file_path.clone(),
+                                total_size: transfer.total_size,
+                                chunks: transfer.chunks.clone(),
+                                validators: transfer.validators.clone(),
+                                supports_ranges: transfer.supports_ranges,
+                            }),
+                        ),
+                        State::Downloading(transfer),
+                    )),
+                    Err(e) => Some(((id, Err(e)), State::Finished)),
+                }
+            }
+            State::Downloading(mut transfer) => {
+                let id = transfer.id;
+                let deadline = tokio::time::Instant::now() + PROGRESS_INTERVAL;
+
+                loop {
+                    match tokio::time::timeout_at(deadline, transfer.receiver.recv()).await {
+                        Ok(Some(SegmentEvent::Advanced(chunk_number, bytes))) => {
+                            if let Some(chunk) = transfer.chunk_mut(chunk_number) {
+                                chunk.downloaded_bytes += bytes;
+                                chunk.status = ChunkStatus::InProgress;
+                            }
+                        }
+                        Ok(Some(SegmentEvent::Rewound(chunk_number, bytes))) => {
+                            if let Some(chunk) = transfer.chunk_mut(chunk_number) {
+                                chunk.downloaded_bytes = bytes;
+                                chunk.status = ChunkStatus::InProgress;
+                            }
+                        }
+                        Ok(Some(SegmentEvent::Finished(chunk_number, status))) => {
+                            if let Some(chunk) = transfer.chunk_mut(chunk_number) {
+                                chunk.status = status;
+                            }
+                            if transfer.is_complete() {
+                                return Some((
+                                    (id, Ok(Progress::Verifying)),
+                                    transfer.into_verifying(),
+                                ));
+                            }
+                        }
+                        Ok(Some(SegmentEvent::Retrying(attempt))) => {
+                            let max_attempts = transfer.retry.max_attempts;
+                            return Some((
+                                (id, Ok(Progress::Retrying(attempt, max_attempts))),
+                                State::Downloading(transfer),
+                            ));
+                        }
+                        Ok(Some(SegmentEvent::Failed(Error::Changed(new_size))))
+                            if transfer.restarts < MAX_RESTARTS =>
+                        {
+                            let old_size = transfer.total_size;
+                            log::info!(
+                                "{} changed on the server ({:?} -> {:?} bytes), restarting",
+                                transfer.url,
+                                old_size,
+                                new_size
+                            );
+                            return Some((
+                                (id, Ok(Progress::Changed { old_size, new_size })),
+                                State::Ready(Box::new(transfer.restart), transfer.restarts + 1),
+                            ));
+                        }
+                        Ok(Some(SegmentEvent::Failed(Error::NotResumable)))
+                            if transfer.restart.settings.restart_unresumable
+                                && transfer.restarts < MAX_RESTARTS =>
+                        {
+                            log::info!("{} can't be resumed, restarting", transfer.url);
+                            return Some((
+                                (id, Ok(Progress::Restarted)),
+                                State::Ready(Box::new(transfer.restart), transfer.restarts + 1),
+                            ));
+                        }
+                        Ok(Some(SegmentEvent::Failed(e))) => {
+                            return Some(((id, Err(e)), State::Finished));
+                        }
+                        Ok(None) if transfer.is_complete() => {
+                            return Some((
+                                (id, Ok(Progress::Verifying)),
+                                transfer.into_verifying(),
+                            ));
+                        }
+                        Ok(None) => {
+                            let result = if transfer.stop.is_cancelled() {
+                                Ok(transfer.paused())
+                            } else {
+                                Err(Error::Download(
+                                    "All connections closed unexpectedly".to_string(),
+                                ))
+                            };
+                            return Some(((id, result), State::Finished));
+                        }
+                        Err(_) => break,
+                    }
+                }
+
+                let progress = transfer.progress();
+                Some(((id, Ok(progress)), State::Downloading(transfer)))
+            }
+            State::Verifying {
+                id,
+                url,
+                headers,
+                file_path,
+                remote_name,
+                checksum,
+            } => {
+                let result = match verify(&url, &headers, &file_path, &remote_name, checksum).await
+                {
+                    Ok(()) => finish(&file_path).await.map(|()| Progress::Finished),
+                    Err(e) => Err(e),
+                };
+                Some(((id, result), State::Finished))
+            }
+            State::Finished => None,
+        }
+    })
+}
+
+enum Setup {
+    Transfer(Box<Transfer>),
+    Skipped(String),
+}
+
+/// Works out the chunk layout (probing the server for a fresh download),
+/// prepares the output file and spawns one task per unfinished chunk.
+async fn start_transfer(job: Job, restarts: u32) -> Result<Setup, Error> {
+    let Job {
+        id,
+        url,
+        mirrors,
+        file_name,
+        headers,
+        file_path,
+        total_size,
+        expected_size,
+        chunks,
+        validators,
+        supports_ranges,
+        checksum,
+        pieces,
+        download_dir,
+        settings,
+        collision,
+        stop,
+        throttle,
+    } = job;
+
+    let retry = RetryPolicy::from_env();
+    let restart = Job {
+        id,
+        url: url.clone(),
+        mirrors: mirrors.clone(),
+        file_name: file_name.clone(),
+        headers: headers.clone(),
+        file_path: file_path.clone(),
+        total_size: None,
+        expected_size,
+        chunks: Vec::new(),
+        validators: Validators::default(),
+        supports_ranges: None,
+        checksum: checksum.clone(),
+        pieces: pieces.clone(),
+        download_dir: download_dir.clone(),
+        settings: settings.clone(),
+        collision,
+        stop: stop.clone(),
+        throttle: throttle.clone(),
+    };
+
+    let has_progress = chunks.iter().any(|chunk| chunk.downloaded_bytes > 0);
+    // The probe's body is the start of the file; the first chunk reads on
+    // from it instead of asking again.
+    let mut first_response = None;
+    // Whichever URL answered the probe comes first; the rest are checked
+    // against it below.
+    let mut primary = url.clone();
+    // Sidecar checksums are looked up next to `url` under the name it
+    // answered with, which a collision rename or a chosen name hides.
+    // Later runs only have the URL to go by.
+    let mut remote_name = None;
+    let (file_path, total_size, chunks, validators, supports_ranges) = if chunks.is_empty() {
+        let (index, probe) =
+            probe_any(&url, &mirrors, &headers, expected_size, &retry, &stop).await?;
+        if index > 0 {
+            primary = mirrors[index - 1].clone();
+        } else {
+            remote_name = Some(probe.file_name.clone());
+        }
+        let total_size = expected_size.or(probe.total_size);
+        let piece_length = pieces.as_ref().map(|pieces| pieces.length);
+        // FTP servers cap how many connections one client gets, so an FTP
+        // file comes over just the one even when it could be resumed.
+        let segmented = probe.supports_ranges && !ftp::is_ftp(&primary);
+        let chunks = plan_chunks(total_size, segmented, piece_length);
+        let file_name = file_name.unwrap_or(probe.file_name);
+        let dir = match download_dir {
+            Some(dir) => crate::settings::expand_home(&dir),
+            None => settings.dir_for(&file_name),
+        };
+        tokio::fs::create_dir_all(&dir).await?;
+        match collision::claim(
+            id,
+            &dir,
+            &file_name,
+            collision,
+            total_size,
+            probe.supports_ranges,
+        ) {
+            Claim::New(file_path) => {
+                first_response = probe.response;
+                (
+                    file_path,
+                    total_size,
+                    chunks,
+                    probe.validators,
+                    Some(probe.supports_ranges),
+                )
+            }
+            Claim::Existing(file_path, len) => (
+                file_path,
+                total_size,
+                skip_existing(chunks, len),
+                probe.validators,
+                Some(probe.supports_ranges),
+            ),
+            Claim::Skip(file_path) => return Ok(Setup::Skipped(file_path)),
+        }
+    } else if file_path.is_empty() {
+        // Partial downloads from before names were stored.
+        let file_path = format!("{}/{}", LEGACY_DOWNLOAD_DIR, naming::legacy_file_name(&url));
+        (file_path, total_size, chunks, validators, supports_ranges)
+    } else if supports_ranges == Some(false) && has_progress {
+        if !settings.restart_unresumable {
+            return Err(Error::NotResumable);
+        }
+        // Starting over truncates the partial file below.
+        let chunks = plan_chunks(total_size, false, None);
+        (file_path, total_size, chunks, validators, supports_ranges)
+    } else {
+        (file_path, total_size, chunks, validators, supports_ranges)
+    };
+
+    // Data goes to a partial file so nothing mistakes it for the real one.
+    let part_path = naming::part_path(&file_path);
+    let fresh = chunks.iter().all(|chunk| chunk.downloaded_bytes == 0);
+    let has_part = tokio::fs::try_exists(&part_path).await?;
+    if !fresh && !has_part && tokio::fs::try_exists(&file_path).await? {
+        // Left at its final name by an older version, or picked up from disk.
+        tokio::fs::rename(&file_path, &part_path).await?;
+    }
+    let file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(fresh)
+        .open(&part_path)
+        .await?;
+    if let (true, Some(total)) = (chunks.len() > 1, total_size) {
+        file.set_len(total).await?;
+    }
+
+    let primary = Source {
+        url: primary,
+        validators: validators.clone(),
+    };
+    let others: Vec<String> = std::iter::once(&url)
+        .chain(&mirrors)
+        .filter(|other| **other != primary.url)
+        .cloned()
+        .collect();
+    let sources = gather_sources(
+        primary,
+        others,
+        &url,
+        &headers,
+        total_size,
+        supports_ranges,
+        &validators,
+    )
+    .await;
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let segment = Arc::new(Segment {
+        sources,
+        origin: url.clone(),
+        headers,
+        part_path,
+        pieces,
+        total_size,
+        sender,
+        stop: stop.clone(),
+        retry,
+        throttle,
+    });
+    let workers = chunks
+        .iter()
+        .filter(|chunk| chunk.status != ChunkStatus::Completed)
+        .cloned()
+        .map(|chunk| {
+            let segment = segment.clone();
+            let response = first_response.take().filter(|_| chunk.position() == 0);
+            tokio::spawn(async move {
+                let chunk_number = chunk.chunk_number;
+                let event = match run_chunk(&segment, chunk, response).await {
+                    Ok(status) => SegmentEvent::Finished(chunk_number, status),
+                    Err(e) => SegmentEvent::Failed(e),
+                };
+                let _ = segment.sender.send(event);
+            })
+        })
+        .collect();
+
+    let remote_name = remote_name.unwrap_or_else(|| url_file_name(&url));
+    Ok(Setup::Transfer(Box::new(Transfer {
+        id,
+        url,
+        file_path,
+        remote_name,
+        checksum,
+        total_size,
+        chunks,
+        validators,
+        supports_ranges,
+        receiver,
+        stop,
+        retry,
+        restart,
+        restarts,
+        _workers: Workers(workers),
+    })))
+}
+
+/// Counts the first `len` bytes as already downloaded, for a file found on
+/// disk that is being carried on with.
+fn skip_existing(chunks: Vec<Chunk>, len: u64) -> Vec<Chunk> {
+    chunks
+        .into_iter()
+        .map(|mut chunk| {
+            let size = chunk.end_byte.map_or(0, |end| end + 1 - chunk.start_byte);
+            chunk.downloaded_bytes = len.saturating_sub(chunk.start_byte).min(size);
+            chunk.status = match chunk.downloaded_bytes {
+                0 => ChunkStatus::Pending,
+                bytes if bytes == size => ChunkStatus::Completed,
+                _ => ChunkStatus::InProgress,
+            };
+            chunk
+        })
+        .collect()
+}
+
+/// Hashes the finished partial file against the expected checksum, or
+/// against one published next to it. Without either there is nothing to check.
+async fn verify(
+    url: &str,
+    headers: &RequestHeaders,
+    file_path: &str,
+    remote_name: &str,
+    checksum: Option<Checksum>,
+) -> Result<(), Error> {
+    let expected = match checksum {
+        Some(checksum) => checksum,
+        None => match checksum::find_sidecar(url, headers, remote_name).await {
+            Some(checksum) => checksum,
+            None => return Ok(()),
+        },
+    };
+
+    let path = naming::part_path(file_path);
+    let algorithm = expected.algorithm;
+    let actual = tokio::task::spawn_blocking(move || checksum::hash_file(&path, algorithm))
+        .await
+        .map_err(|e| Error::Download(e.to_string()))??;
+
+    if expected.matches(&actual) {
+        Ok(())
+    } else {
+        Err(Error::ChecksumMismatch { expected, actual })
+    }
+}
+
+/// Flushes the partial file to disk and moves it to its final name in one
+/// step, so the file only ever appears there complete.
+async fn finish(file_path: &str) -> Result<(), Error> {
+    let part_path = naming::part_path(file_path);
+    File::options()
+        .write(true)
+        .open(&part_path)
+        .await?
+        .sync_all()
+        .await?;
+    tokio::fs::rename(&part_path, file_path).await?;
+
+    // Make the rename itself durable too.
+    #[cfg(unix)]
+    if let Some(dir) = std::path::Path::new(file_path).parent() {
+        if let Ok(dir) = File::open(dir).await {
+            let _ = dir.sync_all().await;
+        }
+    }
+    Ok(())
+}
+
+/// What the first response tells us about a download, and the response
+/// itself, whose body is the file from its first byte. FTP has no such
+/// response, as asking about a file doesn't fetch it there.
+struct Probe {
+    total_size: Option<u64>,
+    supports_ranges: bool,
+    file_name: String,
+    validators: Validators,
+    response: Option<reqwest::Response>,
+}
+
+impl Probe {
+    fn from_listing(url: &str, listing: ftp::Listing) -> Self {
+        Self {
+            total_size: listing.size,
+            supports_ranges: listing.resumable,
+            file_name: url_file_name(url),
+            validators: Validators {
+                etag: None,
+                last_modified: listing.modified,
+            },
+            response: None,
+        }
+    }
+}
+
+/// The name the last part of `url` gives the file.
+fn url_file_name(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(url) => naming::file_name(&url, &reqwest::header::HeaderMap::new()),
+        Err(_) => naming::legacy_file_name(url),
+    }
+}
+
+/// Asks for the whole file as an open range: a `206` tells us ranges work
+/// and carries the full size in `Content-Range`, a `200` means one stream it
+/// is, and `Accept-Ranges: none` rules ranges out either way. The file is
+/// named from the same response, after any redirects. FTP URLs are asked
+/// about instead.
+async fn probe(
+    url: &str,
+    origin: &str,
+    headers: &RequestHeaders,
+    retry: &RetryPolicy,
+    stop: &CancellationToken,
+) -> Result<Probe, Error> {
+    let mut attempt = 0;
+    let response = loop {
+        let error = if ftp::is_ftp(url) {
+            match ftp::probe(url, origin, headers).await {
+                Ok(listing) => return Ok(Probe::from_listing(url, listing)),
+                Err(e) => e,
+            }
+        } else {
+            let request = http::client()
+                .get(url)
+                .header(reqwest::header::RANGE, "bytes=0-");
+            match headers
+                .send(request, url, origin)
+                .await
+                .map_err(Error::from)
+            {
+                Ok(response) => match check_status(response) {
+                    Ok(response) => break response,
+                    Err(e) => e,
+                },
+                Err(e) => e,
+            }
+        };
+
+        attempt += 1;
+        let Some(delay) = retry.delay(attempt, &error) else {
+            return Err(error);
+        };
+        log::debug!(
+            "Probing {} failed ({}), retrying in {:?}",
+            url,
+            error,
+            delay
+        );
+        tokio::select! {
+            _ = stop.cancelled() => return Err(error),
+            _ = tokio::time::sleep(delay) => {}
+        }
+    };
+
+    let file_name = naming::file_name(response.url(), response.headers());
+    let validators = Validators::from_headers(response.headers());
+    let accepts_ranges = response
+        .headers()
+        .get(reqwest::header::ACCEPT_RANGES)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().eq_ignore_ascii_case("bytes"));
+    if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        let total_size = response
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|ct_range| ct_range.to_str().ok())
+            .and_then(|ct_range| ct_range.split('/').next_back())
+            .and_then(|size| size.parse::<u64>().ok());
+        Ok(Probe {
+            total_size,
+            // `Accept-Ranges: none` wins over a 206 from a confused proxy.
+            supports_ranges: total_size.is_some() && accepts_ranges != Some(false),
+            file_name,
+            validators,
+            response: Some(response),
+        })
+    } else {
+        Ok(Probe {
+            total_size: response.content_length(),
+            supports_ranges: false,
+            file_name,
+            validators,
+            response: Some(response),
+        })
+    }
+}
+
+/// Probes `url`, then each mirror in turn until one answers. Returns which
+/// one did (0 for `url`) along with what it said. Only the last one tried
+/// gets retries; before that, the next mirror is the better bet.
+async fn probe_any(
+    url: &str,
+    mirrors: &[String],
+    headers: &RequestHeaders,
+    expected_size: Option<u64>,
+    retry: &RetryPolicy,
+    stop: &CancellationToken,
+) -> Result<(usize, Probe), Error> {
+    let once = RetryPolicy {
+        max_attempts: 0,
+        ..*retry
+    };
+    let origin = url;
+    let urls: Vec<&str> = std::iter::once(url)
+        .chain(mirrors.iter().map(String::as_str))
+        .collect();
+
+    let mut error = None;
+    for (index, url) in urls.iter().enumerate() {
+        if let Some(e) = &error {
+            if stop.is_cancelled() {
+                break;
+            }
+            log::warn!("Probing failed ({}), trying mirror {}", e, url);
+        }
+        let policy = if index + 1 == urls.len() {
+            retry
+        } else {
+            &once
+        };
+        match probe(url, origin, headers, policy, stop).await {
+            Ok(probe) => match (expected_size, probe.total_size) {
+                (Some(expected), Some(actual)) if expected != actual => {
+                    error = Some(Error::SizeMismatch { expected, actual })
+                }
+                _ => return Ok((index, probe)),
+            },
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap())
+}
+
+/// Puts `primary` together with whichever of `others` serve the same file.
+/// Without a known size there's nothing to check mirrors against, so they
+/// sit this one out.
+async fn gather_sources(
+    primary: Source,
+    others: Vec<String>,
+    origin: &str,
+    headers: &RequestHeaders,
+    total_size: Option<u64>,
+    supports_ranges: Option<bool>,
+    validators: &Validators,
+) -> Sources {
+    let Some(total_size) = total_size.filter(|_| !others.is_empty()) else {
+        return Sources::new(vec![primary]);
+    };
+
+    let needs_ranges = supports_ranges == Some(true);
+    let checks = others
+        .iter()
+        .map(|url| mirrors::check(url, origin, headers, total_size, needs_ranges, validators));
+    let results = futures::future::join_all(checks).await;
+
+    let mut sources = vec![primary];
+    for (url, result) in others.into_iter().zip(results) {
+        match result {
+            Ok(source) => sources.push(source),
+            Err(e) => log::warn!("Not using mirror {}: {}", url, e),
+        }
+    }
+    Sources::new(sources)
+}
+
+/// Like `error_for_status`, but keeps the `Retry-After` a 429 or 503 comes
+/// with, and which server wants a login on a 401.
+fn check_status(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(Error::Unauthorized {
+            host: response.url().host_str().unwrap_or_default().to_string(),
+            realm: auth::realm(response.headers()),
+        });
+    }
+    if status.is_client_error() || status.is_server_error() {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        return Err(Error::Status(status, retry_after));
+    }
+    Ok(response)
+}
+
+/// What every chunk worker of one transfer shares.
+struct Segment {
+    sources: Sources,
+    /// The download's own URL, which decides where the cookie may go.
+    origin: String,
+    headers: RequestHeaders,
+    part_path: String,
+    pieces: Option<PieceHashes>,
+    total_size: Option<u64>,
+    sender: mpsc::UnboundedSender<SegmentEvent>,
+    stop: CancellationToken,
+    retry: RetryPolicy,
+    throttle: Arc<TokenBucket>,
+}
+
+/// Downloads a chunk, retrying transient failures from wherever it got to.
+/// The attempt count starts over whenever a retry makes progress. The first
+/// attempt reads from `response` when the probe already opened one.
+///
+/// With mirrors, a failure moves the chunk on to the next one straight away,
+/// and only once every mirror has failed is there a wait before going round
+/// again. A mirror that serves the wrong file is dropped for good.
+async fn run_chunk(
+    segment: &Segment,
+    mut chunk: Chunk,
+    mut response: Option<reqwest::Response>,
+) -> Result<ChunkStatus, Error> {
+    let sources = &segment.sources;
+    let mut source = sources.first_for(chunk.chunk_number);
+    let mut attempt = 0;
+    // Failovers since the last wait.
+    let mut tried = 0;
+    loop {
+        let downloaded_before = chunk.downloaded_bytes;
+        let error = match download_chunk(segment, source, &mut chunk, response.take()).await {
+            Ok(ChunkStatus::Completed) => match check_pieces(segment, &mut chunk).await {
+                Ok(()) => return Ok(ChunkStatus::Completed),
+                Err(e) => e,
+            },
+            Ok(status) => return Ok(status),
+            Err(e) => e,
+        };
+        let url = &sources.get(source).url;
+
+        if chunk.downloaded_bytes > downloaded_before {
+            attempt = 0;
+            tried = 0;
+        }
+
+        // A change to the file the download was probed on starts it over;
+        // a mirror that disagrees with it is the mirror's problem.
+        let mirror_at_fault = match &error {
+            Error::Changed(_) => source != 0,
+            Error::NotResumable | Error::Unauthorized { .. } | Error::Status(..) => {
+                !error.is_retryable()
+            }
+            _ => false,
+        };
+        if mirror_at_fault {
+            if !sources.drop_source(source) {
+                return Err(error);
+            }
+            log::warn!("Dropping {} ({})", url, error);
+            source = sources.next(source).unwrap_or(source);
+            continue;
+        }
+
+        let next = sources.next(source).unwrap_or(source);
+        tried += 1;
+        if error.is_retryable() && next != source && tried < sources.live() {
+            log::info!(
+                "Chunk {} failed on {} ({}), switching to {}",
+                chunk.chunk_number,
+                url,
+                error,
+                sources.get(next).url
+            );
+            source = next;
+            continue;
+        }
+
+        tried = 0;
+        attempt += 1;
+        let Some(delay) = segment.retry.delay(attempt, &error) else {
+            return Err(error);
+        };
+
+        log::debug!(
+            "Chunk {} of {} failed ({}), retrying in {:?}",
+            chunk.chunk_number,
+            url,
+            error,
+            delay
+        );
+        let _ = segment.sender.send(SegmentEvent::Retrying(attempt));
+        tokio::select! {
+            _ = segment.stop.cancelled() => return Ok(ChunkStatus::InProgress),
+            _ = tokio::time::sleep(delay) => {}
+        }
+        source = next;
+    }
+}
+
+/// Where a chunk's bytes come from.
+enum Body {
+    Http(reqwest::Response),
+    Ftp(Box<ftp::Retrieval>),
+}
+
+impl Body {
+    async fn chunk(&mut self) -> Result<Option<bytes::Bytes>, Error> {
+        match self {
+            Body::Http(response) => Ok(response.chunk().await?),
+            Body::Ftp(retrieval) => retrieval.chunk().await,
+        }
+    }
+}
+
+async fn download_chunk(
+    segment: &Segment,
+    source: usize,
+    chunk: &mut Chunk,
+    response: Option<reqwest::Response>,
+) -> Result<ChunkStatus, Error> {
+    let source = segment.sources.get(source);
+    let mut body = if ftp::is_ftp(&source.url) {
+        let retrieval = ftp::retrieve(
+            &source.url,
+            &segment.origin,
+            &segment.headers,
+            chunk.position(),
+            &source.validators,
+            segment.total_size,
+        )
+        .await?;
+        Body::Ftp(Box::new(retrieval))
+    } else {
+        Body::Http(request_range(segment, source, chunk, response).await?)
+    };
+
+    let mut file = File::options().write(true).open(&segment.part_path).await?;
+    file.seek(SeekFrom::Start(chunk.position())).await?;
+
+    // Only worth judging a mirror's speed when there's another to move to.
+    let failover = segment.sources.live() > 1;
+    let stall_timeout = if failover {
+        mirrors::SPEED_WINDOW
+    } else {
+        Duration::MAX
+    };
+    let mut window_start = tokio::time::Instant::now();
+    let mut window_bytes = 0;
+    // Time spent held back by the speed limit, which isn't the mirror's fault.
+    let mut window_throttled = Duration::ZERO;
+
+    loop {
+        // Pausing waits for the write in flight, so the reported offset is exact.
+        let bytes = tokio::select! {
+            _ = segment.stop.cancelled() => {
+                file.flush().await?;
+                return Ok(ChunkStatus::InProgress);
+            }
+            bytes = tokio::time::timeout(stall_timeout, body.chunk()) => match bytes {
+                Ok(bytes) => match bytes? {
+                    Some(bytes) => bytes,
+                    None => break,
+                },
+                Err(_) => {
+                    file.flush().await?;
+                    return Err(Error::TooSlow);
+                }
+            },
+        };
+
+        // Never write past the end of our range, even if the server does.
+        let len = match chunk.remaining() {
+            Some(remaining) => (bytes.len() as u64).min(remaining) as usize,
+            None => bytes.len(),
+        };
+
+        // Holding back here also stops reading, so TCP slows the sender down.
+        let throttled_at = tokio::time::Instant::now();
+        tokio::select! {
+            _ = segment.stop.cancelled() => {
+                file.flush().await?;
+                return Ok(ChunkStatus::InProgress);
+            }
+            _ = async {
+                throttle::global().acquire(len as u64).await;
+                segment.throttle.acquire(len as u64).await;
+            } => {}
+        }
+        window_throttled += throttled_at.elapsed();
+
+        file.write_all(&bytes[..len]).await?;
+        chunk.downloaded_bytes += len as u64;
+        let _ = segment
+            .sender
+            .send(SegmentEvent::Advanced(chunk.chunk_number, len as u64));
+
+        window_bytes += len as u64;
+        let elapsed = window_start.elapsed().saturating_sub(window_throttled);
+        if failover && elapsed >= mirrors::SPEED_WINDOW {
+            if (window_bytes as f64 / elapsed.as_secs_f64()) < mirrors::MIN_SPEED as f64 {
+                file.flush().await?;
+                return Err(Error::TooSlow);
+            }
+            window_start = tokio::time::Instant::now();
+            window_bytes = 0;
+            window_throttled = Duration::ZERO;
+        }
+
+        if chunk.remaining() == Some(0) {
+            break;
+        }
+    }
+    file.flush().await?;
+
+    if chunk.remaining().is_some_and(|remaining| remaining > 0) {
+        return Err(Error::Network(format!(
+            "Connection closed before chunk {} was complete",
+            chunk.chunk_number
+        )));
+    }
+    Ok(ChunkStatus::Completed)
+}
+
+/// Asks `source` for the rest of `chunk`, or takes the probe's `response`,
+/// which already covers it.
+async fn request_range(
+    segment: &Segment,
+    source: &Source,
+    chunk: &Chunk,
+    response: Option<reqwest::Response>,
+) -> Result<reqwest::Response, Error> {
+    let range = chunk.range_header();
+    // Only send the range if the file is still the one we started on.
+    let if_range = range.as_ref().and(source.validators.if_range());
+    let response = match response {
+        Some(response) => response,
+        None => {
+            let mut request = http::client().get(&source.url);
+            if let Some(range) = &range {
+                request = request.header(reqwest::header::RANGE, range);
+            }
+            if let Some(validator) = if_range {
+                request = request.header(reqwest::header::IF_RANGE, validator);
+            }
+            let response = segment
+                .headers
+                .send(request, &source.url, &segment.origin)
+                .await?;
+            check_status(response)?
+        }
+    };
+    // A range covering the whole file is as good as the whole file, which
+    // is all a server without range support can send.
+    let whole_file = chunk.position() == 0
+        && response.status() == reqwest::StatusCode::OK
+        && response.content_length().is_some()
+        && response.content_length() == chunk.end_byte.map(|end| end + 1);
+    if range.is_some() && response.status() != reqwest::StatusCode::PARTIAL_CONTENT && !whole_file {
+        if if_range.is_some() && response.status() == reqwest::StatusCode::OK {
+            return Err(Error::Changed(response.content_length()));
+        }
+        return Err(Error::NotResumable);
+    }
+    Ok(response)
+}
+
+/// Hashes the pieces that lie wholly inside a completed chunk. On a
+/// mismatch the chunk is wound back to the first bad piece, so the rest is
+/// fetched again (from the next mirror, if there is one).
+async fn check_pieces(segment: &Segment, chunk: &mut Chunk) -> Result<(), Error> {
+    let (Some(pieces), Some(total_size), Some(end)) =
+        (&segment.pieces, segment.total_size, chunk.end_byte)
+    else {
+        return Ok(());
+    };
+
+    let first = chunk.start_byte.div_ceil(pieces.length) as usize;
+    for index in first..pieces.hashes.len() {
+        let (start, piece_end) = pieces.range(index, total_size);
+        if piece_end > end + 1 {
+            break;
+        }
+
+        let path = segment.part_path.clone();
+        let algorithm = pieces.algorithm;
+        let actual = tokio::task::spawn_blocking(move || {
+            checksum::hash_range(&path, start, piece_end - start, algorithm)
+        })
+        .await
+        .map_err(|e| Error::Download(e.to_string()))??;
+
+        if !actual.eq_ignore_ascii_case(&pieces.hashes[index]) {
+            chunk.downloaded_bytes = start - chunk.start_byte;
+            chunk.status = ChunkStatus::InProgress;
+            let _ = segment.sender.send(SegmentEvent::Rewound(
+                chunk.chunk_number,
+                chunk.downloaded_bytes,
+            ));
+            return Err(Error::PieceMismatch(index));
+        }
+    }
+    Ok(())
+}
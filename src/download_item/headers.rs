@@ -0,0 +1,103 @@
+use reqwest::header::{HeaderName, HeaderValue, COOKIE, REFERER, USER_AGENT};
+use reqwest::{RequestBuilder, Response, Url};
+
+use super::auth::{self, Credentials};
+
+/// Extra request headers one download needs, such as the session cookie or
+/// `Referer` a vendor portal insists on. Sent with every request for the
+/// file, including on resume.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RequestHeaders {
+    /// Anything else, as name and value.
+    pub headers: Vec<(String, String)>,
+    pub cookie: Option<String>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    /// Login for the download's own host, used when the server asks for one.
+    /// Kept apart from the rest when saved.
+    pub credentials: Option<Credentials>,
+}
+
+impl RequestHeaders {
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+            && self.cookie.is_none()
+            && self.user_agent.is_none()
+            && self.referer.is_none()
+    }
+
+    /// Adds the headers to a request for `url`. The cookie and the custom
+    /// headers, which may carry a token, only go to the host of `origin`,
+    /// the URL they were given for, never to mirrors.
+    fn apply(&self, mut request: RequestBuilder, url: &str, origin: &str) -> RequestBuilder {
+        let own_host = same_host(url, origin);
+        for (name, value) in self.headers.iter().filter(|_| own_host) {
+            match (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                (Ok(name), Ok(value)) => request = request.header(name, value),
+                _ => log::warn!("Skipping invalid header {}", name),
+            }
+        }
+        if let Some(user_agent) = &self.user_agent {
+            request = request.header(USER_AGENT, user_agent);
+        }
+        if let Some(referer) = &self.referer {
+            request = request.header(REFERER, referer);
+        }
+        if let Some(cookie) = self.cookie.as_ref().filter(|_| own_host) {
+            request = request.header(COOKIE, cookie);
+        }
+        request
+    }
+
+    /// Applies the headers and sends the request, logging in if the server
+    /// asks and a login is known for it.
+    pub async fn send(
+        &self,
+        request: RequestBuilder,
+        url: &str,
+        origin: &str,
+    ) -> reqwest::Result<Response> {
+        auth::send(
+            self.apply(request, url, origin),
+            origin,
+            self.credentials.as_ref(),
+        )
+        .await
+    }
+
+    /// The extra headers one per line, `Name: value`, as they are stored.
+    pub fn headers_text(&self) -> String {
+        self.headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Reads back what `headers_text` wrote.
+    pub fn parse_headers(text: &str) -> Vec<(String, String)> {
+        text.lines().filter_map(parse_header).collect()
+    }
+}
+
+/// Splits `Name: value`, checking both halves are valid in a request.
+pub fn parse_header(line: &str) -> Option<(String, String)> {
+    let (name, value) = line.split_once(':')?;
+    let (name, value) = (name.trim(), value.trim());
+    HeaderName::from_bytes(name.as_bytes()).ok()?;
+    HeaderValue::from_str(value).ok()?;
+    Some((name.to_string(), value.to_string()))
+}
+
+fn same_host(url: &str, origin: &str) -> bool {
+    let host = |url: &str| {
+        Url::parse(url)
+            .ok()?
+            .host_str()
+            .map(str::to_ascii_lowercase)
+    };
+    host(url).is_some() && host(url) == host(origin)
+}
@@ -0,0 +1,242 @@
+use reqwest::Url;
+use roxmltree::{Document, Node};
+
+use super::checksum::{Checksum, HashAlgorithm, PieceHashes};
+use super::ftp;
+use super::headers::RequestHeaders;
+use super::mirrors::Mirror;
+use super::naming;
+use crate::utils::http;
+
+/// What the add flow reports for a metalink before it has been loaded.
+pub const CONTENT_TYPE: &str = "application/metalink4+xml";
+/// Metalinks list URLs and hashes; anything this big is something else.
+const MAX_METALINK_SIZE: usize = 16 * 1024 * 1024;
+
+/// One `<file>` of a metalink, ready to become a download.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetalinkFile {
+    pub name: String,
+    pub size: Option<u64>,
+    pub checksum: Option<Checksum>,
+    pub pieces: Option<PieceHashes>,
+    /// Best priority first.
+    pub mirrors: Vec<Mirror>,
+}
+
+/// Whether `location` (a URL or a path) names a metalink, by its content
+/// type when one is known or else by its extension.
+pub fn is_metalink(location: &str, content_type: Option<&str>) -> bool {
+    if let Some(content_type) = content_type {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        if mime.eq_ignore_ascii_case(CONTENT_TYPE)
+            || mime.eq_ignore_ascii_case("application/metalink+xml")
+        {
+            return true;
+        }
+    }
+
+    let path = match Url::parse(location) {
+        Ok(url) => url.path().to_string(),
+        Err(_) => location.to_string(),
+    };
+    let path = path.to_ascii_lowercase();
+    path.ends_with(".meta4") || path.ends_with(".metalink")
+}
+
+/// Reads a metalink from an http(s) URL, a `file://` URL or a local path.
+pub async fn load(location: &str, headers: &RequestHeaders) -> Result<Vec<MetalinkFile>, String> {
+    let xml = match Url::parse(location) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => fetch(url, headers).await?,
+        Ok(url) if url.scheme() == "file" => {
+            let path = url
+                .to_file_path()
+                .map_err(|_| format!("Not a local path: {}", location))?;
+            read(&path.to_string_lossy()).await?
+        }
+        _ => read(location).await?,
+    };
+    parse(&xml)
+}
+
+async fn fetch(url: Url, headers: &RequestHeaders) -> Result<String, String> {
+    let response = headers
+        .send(http::client().get(url.clone()), url.as_str(), url.as_str())
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| e.to_string())?;
+    if response
+        .content_length()
+        .is_some_and(|len| len > MAX_METALINK_SIZE as u64)
+    {
+        return Err("Metalink is too big".to_string());
+    }
+    let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+    if bytes.len() > MAX_METALINK_SIZE {
+        return Err("Metalink is too big".to_string());
+    }
+    String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+}
+
+async fn read(path: &str) -> Result<String, String> {
+    let path = crate::settings::expand_home(path);
+    tokio::fs::read_to_string(&path)
+        .await
+        .map_err(|e| format!("Couldn't read {}: {}", path, e))
+}
+
+/// Parses a Metalink 4 (RFC 5854) document, or an older Metalink 3 one.
+/// Files without a single http(s) or ftp(s) URL are left out.
+pub fn parse(xml: &str) -> Result<Vec<MetalinkFile>, String> {
+    let document = Document::parse(xml).map_err(|e| format!("Invalid metalink: {}", e))?;
+    if document.root_element().tag_name().name() != "metalink" {
+        return Err("Not a metalink".to_string());
+    }
+
+    let files: Vec<MetalinkFile> = document
+        .descendants()
+        .filter(|node| node.tag_name().name() == "file")
+        .filter_map(|node| {
+            let file = parse_file(node);
+            if file.is_none() {
+                log::warn!(
+                    "Skipping metalink file {:?}: no usable URLs",
+                    node.attribute("name")
+                );
+            }
+            file
+        })
+        .collect();
+
+    if files.is_empty() {
+        return Err("No downloadable files in the metalink".to_string());
+    }
+    Ok(files)
+}
+
+fn parse_file(file: Node) -> Option<MetalinkFile> {
+    let name = naming::sanitize(file.attribute("name").unwrap_or_default());
+
+    let mut mirrors: Vec<Mirror> = children(file, "url")
+        .chain(children(file, "resources").flat_map(|resources| children(resources, "url")))
+        .filter_map(parse_url)
+        .collect();
+    if mirrors.is_empty() {
+        return None;
+    }
+    // Stable, so equal priorities keep the order they were listed in.
+    mirrors.sort_by_key(|mirror| mirror.priority.unwrap_or(u32::MAX));
+
+    // Metalink 3 keeps hashes and pieces inside `<verification>`.
+    let verification: Vec<Node> = std::iter::once(file)
+        .chain(children(file, "verification"))
+        .collect();
+    let checksum = best(
+        verification
+            .iter()
+            .flat_map(|node| children(*node, "hash"))
+            .filter_map(|hash| {
+                let algorithm = HashAlgorithm::from_name(hash.attribute("type")?)?;
+                let hex = text(hash)?;
+                Checksum::parse(&format!("{}:{}", algorithm, hex))
+            }),
+        |checksum| checksum.algorithm,
+    );
+    let pieces = best(
+        verification
+            .iter()
+            .flat_map(|node| children(*node, "pieces"))
+            .filter_map(parse_pieces),
+        |pieces| pieces.algorithm,
+    );
+
+    Some(MetalinkFile {
+        name,
+        size: children(file, "size")
+            .next()
+            .and_then(text)
+            .and_then(|size| size.parse().ok()),
+        checksum,
+        pieces,
+        mirrors,
+    })
+}
+
+/// `<url priority="1" location="de">` in Metalink 4. Metalink 3 instead has
+/// `preference`, from 100 down, which is turned around to match.
+fn parse_url(url: Node) -> Option<Mirror> {
+    let href = text(url)?;
+    let parsed = Url::parse(&href).ok()?;
+    if !matches!(parsed.scheme(), "http" | "https") && !ftp::is_ftp(&href) {
+        return None;
+    }
+
+    let number = |name| url.attribute(name)?.trim().parse::<u32>().ok();
+    let priority = number("priority").or_else(|| {
+        number("preference").map(|preference| 101u32.saturating_sub(preference.min(100)))
+    });
+    Some(Mirror {
+        url: href,
+        priority,
+        location: url
+            .attribute("location")
+            .map(|location| location.trim().to_ascii_lowercase())
+            .filter(|location| !location.is_empty()),
+    })
+}
+
+fn parse_pieces(pieces: Node) -> Option<PieceHashes> {
+    let algorithm = HashAlgorithm::from_name(pieces.attribute("type")?)?;
+    let length = pieces
+        .attribute("length")?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|length| *length > 0)?;
+
+    let mut hashes: Vec<(usize, String)> = children(pieces, "hash")
+        .enumerate()
+        .map(|(index, hash)| {
+            // Metalink 3 numbers its pieces; 4 relies on document order.
+            let index = hash
+                .attribute("piece")
+                .and_then(|piece| piece.trim().parse().ok())
+                .unwrap_or(index);
+            Some((index, text(hash)?))
+        })
+        .collect::<Option<_>>()?;
+    hashes.sort_by_key(|(index, _)| *index);
+
+    let hashes = hashes
+        .into_iter()
+        .map(|(_, hex)| Checksum::parse(&format!("{}:{}", algorithm, hex)).map(|c| c.hex))
+        .collect::<Option<Vec<_>>>()?;
+    (!hashes.is_empty()).then_some(PieceHashes {
+        algorithm,
+        length,
+        hashes,
+    })
+}
+
+/// Child elements by local name, whatever namespace the document uses.
+fn children<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children()
+        .filter(move |child| child.tag_name().name() == name)
+}
+
+fn text(node: Node) -> Option<String> {
+    let text = node.text()?.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// The one made with the strongest hash we support.
+fn best<T>(items: impl Iterator<Item = T>, algorithm: impl Fn(&T) -> HashAlgorithm) -> Option<T> {
+    items.min_by_key(|item| {
+        HashAlgorithm::BY_STRENGTH
+            .iter()
+            .position(|candidate| *candidate == algorithm(item))
+    })
+}
@@ -0,0 +1,167 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use reqwest::header::CONTENT_RANGE;
+use reqwest::StatusCode;
+
+use super::download::Validators;
+use super::ftp;
+use super::headers::RequestHeaders;
+use crate::utils::http;
+
+/// A mirror delivering less than this while others are available gets
+/// swapped for the next one.
+pub const MIN_SPEED: u64 = 16 * 1024;
+/// How long a mirror gets to prove it is fast enough, and how long a
+/// connection may sit without data before it's given up on.
+pub const SPEED_WINDOW: Duration = Duration::from_secs(15);
+
+/// Another URL for a download's file, as the user or a metalink gave it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Mirror {
+    pub url: String,
+    /// Lower is preferred, as in metalinks; `None` comes after the rest.
+    pub priority: Option<u32>,
+    /// Where the mirror is, usually an ISO 3166 country code.
+    pub location: Option<String>,
+}
+
+impl Mirror {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            ..Self::default()
+        }
+    }
+}
+
+/// One URL the file can be fetched from.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub url: String,
+    /// What this server reports for the file, sent back in `If-Range`.
+    pub validators: Validators,
+}
+
+/// The URLs one transfer pulls from. The first is the one the download was
+/// probed on; a mirror that turns out to serve something else is dropped.
+#[derive(Debug)]
+pub struct Sources {
+    sources: Vec<Source>,
+    dropped: Vec<AtomicBool>,
+}
+
+impl Sources {
+    pub fn new(sources: Vec<Source>) -> Self {
+        let dropped = sources.iter().map(|_| AtomicBool::new(false)).collect();
+        Self { sources, dropped }
+    }
+
+    pub fn get(&self, index: usize) -> &Source {
+        &self.sources[index]
+    }
+
+    /// How many are still in use.
+    pub fn live(&self) -> usize {
+        self.dropped
+            .iter()
+            .filter(|dropped| !dropped.load(Ordering::Relaxed))
+            .count()
+    }
+
+    /// Where chunk `chunk_number` starts, spreading chunks across mirrors.
+    pub fn first_for(&self, chunk_number: u32) -> usize {
+        let start = chunk_number as usize % self.sources.len();
+        self.next_from(start).unwrap_or(0)
+    }
+
+    /// The next source in use after `index`, wrapping around, which is
+    /// `index` itself when it's the only one left.
+    pub fn next(&self, index: usize) -> Option<usize> {
+        self.next_from(index + 1)
+    }
+
+    fn next_from(&self, start: usize) -> Option<usize> {
+        let len = self.sources.len();
+        (0..len)
+            .map(|offset| (start + offset) % len)
+            .find(|index| !self.dropped[*index].load(Ordering::Relaxed))
+    }
+
+    /// Stops using a source. Returns whether any are left.
+    pub fn drop_source(&self, index: usize) -> bool {
+        self.dropped[index].store(true, Ordering::Relaxed);
+        self.live() > 0
+    }
+}
+
+/// Makes sure `url` serves the same file as the download: the same size
+/// and, where both servers give one, the same ETag. Segmented downloads also
+/// need it to take ranges.
+pub async fn check(
+    url: &str,
+    origin: &str,
+    headers: &RequestHeaders,
+    total_size: u64,
+    needs_ranges: bool,
+    validators: &Validators,
+) -> Result<Source, String> {
+    if ftp::is_ftp(url) {
+        // FTP has no ETag; its `MDTM` date is only comparable with itself.
+        let listing = ftp::probe(url, origin, headers)
+            .await
+            .map_err(|e| e.to_string())?;
+        if listing.size != Some(total_size) {
+            return Err(format!("size {:?} instead of {}", listing.size, total_size));
+        }
+        if needs_ranges && !listing.resumable {
+            return Err("no REST support".to_string());
+        }
+        return Ok(Source {
+            url: url.to_string(),
+            validators: Validators {
+                etag: None,
+                last_modified: listing.modified,
+            },
+        });
+    }
+
+    let request = http::client()
+        .get(url)
+        .header(reqwest::header::RANGE, "bytes=0-0");
+    let response = headers
+        .send(request, url, origin)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("server responded with {}", response.status()));
+    }
+
+    let size = if response.status() == StatusCode::PARTIAL_CONTENT {
+        response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split('/').next_back())
+            .and_then(|size| size.parse::<u64>().ok())
+    } else if needs_ranges {
+        return Err("no range support".to_string());
+    } else {
+        response.content_length()
+    };
+    if size != Some(total_size) {
+        return Err(format!("size {:?} instead of {}", size, total_size));
+    }
+
+    let mirror_validators = Validators::from_headers(response.headers());
+    if let (Some(expected), Some(actual)) = (&validators.etag, &mirror_validators.etag) {
+        if expected != actual {
+            return Err(format!("ETag {} instead of {}", actual, expected));
+        }
+    }
+
+    Ok(Source {
+        url: url.to_string(),
+        validators: mirror_validators,
+    })
+}
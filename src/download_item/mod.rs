@@ -0,0 +1,722 @@
+use iced::Subscription;
+use iced::{
+    widget::{button, checkbox, column, row, text, text_input},
+    Element, Task,
+};
+use std::fmt::Display;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+use auth::Credentials;
+use checksum::{Checksum, PieceHashes};
+use collision::CollisionPolicy;
+use download::{Chunk, Validators};
+use headers::RequestHeaders;
+use metalink::MetalinkFile;
+use mirrors::Mirror;
+use throttle::TokenBucket;
+
+use crate::settings::Settings;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum DownloadStatus {
+    #[default]
+    Pending,
+    /// Waiting for one of the active download slots to free up.
+    Queued,
+    InProgress {
+        progress: f32,
+        downloaded_bytes: u64,
+    },
+    Paused {
+        progress: f32,
+        downloaded_bytes: u64,
+    },
+    Verifying,
+    Completed,
+    ChecksumMismatch(String),
+    /// The file was already there and the collision policy said to keep it.
+    Skipped,
+    Cancelled,
+    Failed(String),
+}
+
+impl Display for DownloadStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadStatus::Pending => write!(f, "Pending"),
+            DownloadStatus::Queued => write!(f, "Queued"),
+            DownloadStatus::InProgress {
+                progress,
+                downloaded_bytes,
+            } => {
+                let size = format_bytes(*downloaded_bytes);
+                write!(f, "InProgress:{}%:{}", progress, size)
+            }
+            DownloadStatus::Paused {
+                progress,
+                downloaded_bytes,
+            } => {
+                let size = format_bytes(*downloaded_bytes);
+                write!(f, "Paused:{}%:{}", progress, size)
+            }
+            DownloadStatus::Verifying => write!(f, "Verifying"),
+            DownloadStatus::Completed => write!(f, "Completed"),
+            DownloadStatus::ChecksumMismatch(msg) => write!(f, "Checksum mismatch: {}", msg),
+            DownloadStatus::Failed(msg) => write!(f, "Failed: {}", msg),
+            DownloadStatus::Skipped => write!(f, "Skipped"),
+            DownloadStatus::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+/// A server turned the download away and is waiting for a login.
+#[derive(Debug, Clone, Default)]
+pub struct LoginPrompt {
+    pub host: String,
+    pub realm: Option<String>,
+    pub username: String,
+    pub password: String,
+    /// Keep the login for every download from `host`.
+    pub remember: bool,
+}
+
+#[derive(Default, Clone)]
+pub struct DownloadItem {
+    pub id: i64,
+    pub url: String,
+    /// Other URLs for the same file, used alongside `url` and in its place
+    /// when it fails.
+    pub mirrors: Vec<Mirror>,
+    /// Name to save as, when it's known up front as with metalinks.
+    pub file_name: Option<String>,
+    /// Cookie, User-Agent, Referer and whatever else the server wants.
+    pub headers: RequestHeaders,
+    pub file_path: String,
+    pub total_size: Option<i64>,
+    /// The size a metalink gave, which the server's file has to match.
+    pub expected_size: Option<u64>,
+    pub status: DownloadStatus,
+    /// Position in the queue; lower starts first.
+    pub priority: i64,
+    pub chunks: Vec<Chunk>,
+    /// ETag and Last-Modified of the file being downloaded.
+    pub validators: Validators,
+    /// Whether the server can resume this download, once that's known.
+    pub supports_ranges: Option<bool>,
+    /// Digest the finished file must match, if one was given.
+    pub checksum: Option<Checksum>,
+    /// Per-piece digests, checked as chunks complete.
+    pub pieces: Option<PieceHashes>,
+    /// Speed cap for this download alone, in bytes per second.
+    pub speed_limit: Option<u64>,
+    /// What to do if the file name is taken; `None` follows the global setting.
+    pub collision_policy: Option<CollisionPolicy>,
+    /// Folder to save into instead of the one the settings pick.
+    pub download_dir: Option<String>,
+    /// Shared with the running transfer so a new limit applies right away.
+    pub throttle: Arc<TokenBucket>,
+    /// Current retry attempt and the most that will be made, while retrying.
+    pub retry_attempt: Option<(u32, u32)>,
+    /// Something the user should know about the current run, like the file
+    /// changing on the server.
+    pub notice: Option<String>,
+    /// Pause was pressed on a download that can't be resumed and is
+    /// waiting for the user to confirm.
+    pub confirm_pause: bool,
+    /// Asking for a user name and password after a `401`.
+    pub login: Option<LoginPrompt>,
+    /// Tells the running transfer to wind down; a fresh one is made per start.
+    pub stop: CancellationToken,
+}
+
+#[derive(Debug, Clone)]
+pub enum DownloadMessage {
+    QueueDownload,
+    StartDownload,
+    Prepared {
+        file_path: String,
+        total_size: Option<u64>,
+        chunks: Vec<Chunk>,
+        validators: Validators,
+        supports_ranges: Option<bool>,
+    },
+    /// The file changed on the server and is being downloaded again:
+    /// size before and after.
+    RemoteChanged(Option<u64>, Option<u64>),
+    /// The server couldn't resume, so the download started over.
+    Restarted,
+    UpdateProgress(f32, u64, Vec<Chunk>),
+    /// Pause pressed in the UI; downloads that can't resume ask first.
+    RequestPause,
+    PauseDownload,
+    KeepDownloading,
+    Paused(f32, u64, Vec<Chunk>),
+    Retrying(u32, u32),
+    Verifying,
+    ChecksumMismatch(String),
+    Skipped(String),
+    SetSpeedLimit(String),
+    CompleteDownload,
+    CancelDownload,
+    /// Throws away the partial file and queues the download from zero.
+    RestartDownload,
+    /// Cancels the download and takes it off the list; the app drops it.
+    RemoveDownload,
+    /// The partial download can't be continued because the server ignores ranges.
+    NotResumable,
+    /// A server wants a login: its host and the realm it named.
+    LoginRequired(String, Option<String>),
+    EditLoginUsername(String),
+    EditLoginPassword(String),
+    RememberLogin(bool),
+    /// Carries on with the login entered; the app saves it if it's for the host.
+    LogIn,
+    DismissLogin,
+    FailDownload(String),
+}
+
+pub mod auth;
+pub mod checksum;
+pub mod collision;
+pub mod download;
+pub mod ftp;
+pub mod headers;
+pub mod metalink;
+pub mod mirrors;
+pub mod naming;
+pub mod retry;
+pub mod throttle;
+
+impl DownloadItem {
+    pub fn new(url: String) -> Self {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+
+        Self {
+            id,
+            status: DownloadStatus::default(),
+            url,
+            mirrors: Vec::new(),
+            file_name: None,
+            headers: RequestHeaders::default(),
+            file_path: String::new(),
+            total_size: None,
+            expected_size: None,
+            priority: 0,
+            chunks: Vec::new(),
+            validators: Validators::default(),
+            supports_ranges: None,
+            checksum: None,
+            pieces: None,
+            speed_limit: None,
+            collision_policy: None,
+            download_dir: None,
+            throttle: Arc::default(),
+            retry_attempt: None,
+            notice: None,
+            confirm_pause: false,
+            login: None,
+            stop: CancellationToken::new(),
+        }
+    }
+
+    /// A download for one file of a metalink, from its best mirror with the
+    /// rest kept as fallbacks.
+    pub fn from_metalink(file: MetalinkFile) -> Self {
+        let mut mirrors = file.mirrors.into_iter();
+        let url = mirrors.next().map(|mirror| mirror.url).unwrap_or_default();
+        let mut item = Self::new(url);
+        item.mirrors = mirrors.collect();
+        item.file_name = Some(file.name).filter(|name| !name.is_empty());
+        item.total_size = file.size.map(|size| size as i64);
+        item.expected_size = file.size;
+        item.checksum = file.checksum;
+        item.pieces = file.pieces;
+        item
+    }
+
+    pub fn update(&mut self, message: DownloadMessage) -> Task<DownloadMessage> {
+        match message {
+            DownloadMessage::QueueDownload => {
+                // Hold on to the name from an earlier run so nothing else takes it.
+                if !self.file_path.is_empty() && !collision::reserve(self.id, &self.file_path) {
+                    log::warn!("{} is already reserved by another download", self.file_path);
+                }
+                self.status = DownloadStatus::Queued;
+                Task::none()
+            }
+            DownloadMessage::StartDownload => {
+                let downloaded_bytes = match self.status {
+                    DownloadStatus::InProgress {
+                        downloaded_bytes, ..
+                    }
+                    | DownloadStatus::Paused {
+                        downloaded_bytes, ..
+                    } => downloaded_bytes,
+                    _ => self.chunks.iter().map(|chunk| chunk.downloaded_bytes).sum(),
+                };
+
+                self.stop = CancellationToken::new();
+                self.retry_attempt = None;
+                self.notice = None;
+                self.status = DownloadStatus::InProgress {
+                    progress: 0.0,
+                    downloaded_bytes,
+                };
+                Task::none()
+            }
+            DownloadMessage::Prepared {
+                file_path,
+                total_size,
+                chunks,
+                validators,
+                supports_ranges,
+            } => {
+                self.file_path = file_path;
+                self.validators = validators;
+                self.supports_ranges = supports_ranges;
+                self.total_size = total_size.map(|size| size as i64);
+                self.chunks = chunks;
+                Task::none()
+            }
+            DownloadMessage::RemoteChanged(old_size, new_size) => {
+                self.notice = Some(match (old_size, new_size) {
+                    (Some(old), Some(new)) if old != new => format!(
+                        "File changed on the server ({} → {}), restarted",
+                        format_bytes(old),
+                        format_bytes(new)
+                    ),
+                    _ => "File changed on the server, restarted".to_string(),
+                });
+                self.chunks.clear();
+                self.status = DownloadStatus::InProgress {
+                    progress: 0.0,
+                    downloaded_bytes: 0,
+                };
+                Task::none()
+            }
+            DownloadMessage::Restarted => {
+                self.notice = Some("Server can't resume, restarted".to_string());
+                self.supports_ranges = Some(false);
+                self.chunks.clear();
+                self.status = DownloadStatus::InProgress {
+                    progress: 0.0,
+                    downloaded_bytes: 0,
+                };
+                Task::none()
+            }
+            DownloadMessage::UpdateProgress(progress, bytes, chunks) => {
+                self.chunks = chunks;
+                if let DownloadStatus::InProgress {
+                    progress: _,
+                    downloaded_bytes: _,
+                } = self.status
+                {
+                    self.status = DownloadStatus::InProgress {
+                        progress,
+                        downloaded_bytes: bytes,
+                    };
+                } else {
+                    self.status = DownloadStatus::InProgress {
+                        progress,
+                        downloaded_bytes: bytes,
+                    };
+                }
+                Task::none()
+            }
+            DownloadMessage::RequestPause if self.supports_ranges == Some(false) => {
+                self.confirm_pause = true;
+                Task::none()
+            }
+            DownloadMessage::RequestPause | DownloadMessage::PauseDownload => {
+                // The transfer reports back with `Paused` once its writes are flushed.
+                self.confirm_pause = false;
+                self.stop.cancel();
+                Task::none()
+            }
+            DownloadMessage::KeepDownloading => {
+                self.confirm_pause = false;
+                Task::none()
+            }
+            DownloadMessage::Paused(progress, bytes, chunks) => {
+                self.chunks = chunks;
+                self.status = DownloadStatus::Paused {
+                    progress,
+                    downloaded_bytes: bytes,
+                };
+                Task::none()
+            }
+            DownloadMessage::Retrying(attempt, max_attempts) => {
+                self.retry_attempt = Some((attempt, max_attempts));
+                Task::none()
+            }
+            DownloadMessage::Verifying => {
+                self.status = DownloadStatus::Verifying;
+                Task::none()
+            }
+            DownloadMessage::ChecksumMismatch(msg) => {
+                // The bytes on disk are wrong, so a restart has to fetch them all again.
+                self.status = DownloadStatus::ChecksumMismatch(msg);
+                self.retry_attempt = None;
+                self.chunks.clear();
+                Task::none()
+            }
+            DownloadMessage::Skipped(file_path) => {
+                collision::release(self.id);
+                self.file_path = file_path;
+                self.status = DownloadStatus::Skipped;
+                self.retry_attempt = None;
+                self.chunks.clear();
+                Task::none()
+            }
+            DownloadMessage::SetSpeedLimit(input) => {
+                if let Some(limit) = throttle::parse_limit(&input) {
+                    self.set_speed_limit(limit);
+                }
+                Task::none()
+            }
+            DownloadMessage::CompleteDownload => {
+                // The finished file itself now keeps the name from being reused.
+                collision::release(self.id);
+                self.status = DownloadStatus::Completed;
+                self.retry_attempt = None;
+                self.chunks.clear();
+                Task::none()
+            }
+            DownloadMessage::CancelDownload | DownloadMessage::RemoveDownload => {
+                self.stop.cancel();
+                collision::release(self.id);
+                self.discard_partial();
+                self.status = DownloadStatus::Cancelled;
+                self.chunks.clear();
+                Task::none()
+            }
+            DownloadMessage::RestartDownload => {
+                self.discard_partial();
+                self.chunks.clear();
+                self.status = DownloadStatus::Cancelled;
+                self.update(DownloadMessage::QueueDownload)
+            }
+            DownloadMessage::NotResumable => {
+                self.supports_ranges = Some(false);
+                self.status = DownloadStatus::Failed(download::Error::NotResumable.to_string());
+                Task::none()
+            }
+            DownloadMessage::LoginRequired(host, realm) => {
+                let error = download::Error::Unauthorized {
+                    host: host.clone(),
+                    realm: realm.clone(),
+                };
+                self.status = DownloadStatus::Failed(error.to_string());
+                self.retry_attempt = None;
+                let own = self
+                    .headers
+                    .credentials
+                    .as_ref()
+                    .filter(|_| self.is_own_host(&host));
+                self.login = Some(LoginPrompt {
+                    username: own.map(|own| own.username.clone()).unwrap_or_default(),
+                    remember: !self.is_own_host(&host),
+                    host,
+                    realm,
+                    ..LoginPrompt::default()
+                });
+                Task::none()
+            }
+            DownloadMessage::EditLoginUsername(username) => {
+                if let Some(login) = &mut self.login {
+                    login.username = username;
+                }
+                Task::none()
+            }
+            DownloadMessage::EditLoginPassword(password) => {
+                if let Some(login) = &mut self.login {
+                    login.password = password;
+                }
+                Task::none()
+            }
+            DownloadMessage::RememberLogin(remember) => {
+                if let Some(login) = &mut self.login {
+                    login.remember = remember;
+                }
+                Task::none()
+            }
+            DownloadMessage::LogIn => {
+                let Some(login) = self.login.take() else {
+                    return Task::none();
+                };
+                if self.is_own_host(&login.host) {
+                    // A login kept for the host only applies without one of our own.
+                    self.headers.credentials = (!login.remember).then_some(Credentials {
+                        username: login.username,
+                        password: login.password,
+                    });
+                }
+                self.update(DownloadMessage::QueueDownload)
+            }
+            DownloadMessage::DismissLogin => {
+                self.login = None;
+                Task::none()
+            }
+            DownloadMessage::FailDownload(msg) => {
+                self.status = DownloadStatus::Failed(msg);
+                Task::none()
+            }
+        }
+    }
+
+    pub fn view(&self) -> Element<'_, DownloadMessage> {
+        let status_text = match &self.status {
+            DownloadStatus::InProgress {
+                progress,
+                downloaded_bytes,
+            } if self.stop.is_cancelled() => {
+                let size = format_bytes(*downloaded_bytes);
+                format!("Pausing: {:.1}% ({})", progress, size)
+            }
+            DownloadStatus::InProgress {
+                progress,
+                downloaded_bytes,
+            } => {
+                let size = format_bytes(*downloaded_bytes);
+                format!("Downloading: {:.1}% ({})", progress, size)
+            }
+            DownloadStatus::Paused {
+                progress,
+                downloaded_bytes,
+            } => {
+                let size = format_bytes(*downloaded_bytes);
+                format!("Paused: {:.1}% ({})", progress, size)
+            }
+            _ => self.status.to_string(),
+        };
+
+        // Resuming would only fail, so offer to start over instead.
+        let resumable = self.supports_ranges != Some(false);
+        let controls = match self.status {
+            DownloadStatus::InProgress { .. } if self.confirm_pause => row![
+                text("The server can't resume this download, pausing loses progress."),
+                button("pause anyway").on_press(DownloadMessage::PauseDownload),
+                button("keep downloading").on_press(DownloadMessage::KeepDownloading),
+            ],
+            DownloadStatus::InProgress { .. } => row![
+                button("pause").on_press_maybe(
+                    (!self.stop.is_cancelled()).then_some(DownloadMessage::RequestPause)
+                ),
+                button("cancel").on_press(DownloadMessage::CancelDownload),
+            ],
+            DownloadStatus::Paused { .. } | DownloadStatus::Failed(_) if !resumable => row![
+                button("restart from zero").on_press(DownloadMessage::RestartDownload),
+                button("cancel").on_press(DownloadMessage::CancelDownload),
+            ],
+            DownloadStatus::Paused { .. } => row![
+                button("resume").on_press(DownloadMessage::StartDownload),
+                button("cancel").on_press(DownloadMessage::CancelDownload),
+            ],
+            DownloadStatus::Queued => {
+                row![button("cancel").on_press(DownloadMessage::CancelDownload)]
+            }
+            DownloadStatus::Verifying | DownloadStatus::Completed => row![],
+            _ => row![button("start download").on_press(DownloadMessage::StartDownload)],
+        };
+
+        let status_text = match self.retry_attempt {
+            Some((attempt, max_attempts))
+                if matches!(self.status, DownloadStatus::InProgress { .. }) =>
+            {
+                format!("{} - retry {}/{}", status_text, attempt, max_attempts)
+            }
+            _ => status_text,
+        };
+        let status_text = match &self.notice {
+            Some(notice) => format!("{} - {}", status_text, notice),
+            None => status_text,
+        };
+
+        let speed_limit = self
+            .speed_limit
+            .map(|limit| (limit / 1024).to_string())
+            .unwrap_or_default();
+        let controls = controls
+            .push(
+                text_input("KB/s limit", &speed_limit)
+                    .on_input(DownloadMessage::SetSpeedLimit)
+                    .width(100),
+            )
+            .push(button("remove").on_press(DownloadMessage::RemoveDownload));
+
+        let file_name = self
+            .file_path
+            .rsplit('/')
+            .next()
+            .filter(|file_name| !file_name.is_empty())
+            .or(self.file_name.as_deref());
+        let title = match file_name {
+            Some(file_name) => format!("{} ({})", file_name, self.url),
+            None => self.url.clone(),
+        };
+        let title = match self.mirrors.len() {
+            0 => title,
+            1 => format!("{} + 1 mirror", title),
+            n => format!("{} + {} mirrors", title, n),
+        };
+        let title = if self.headers.is_empty() {
+            title
+        } else {
+            format!("{} [custom headers]", title)
+        };
+
+        column![text(title), controls.spacing(10), text(status_text)]
+            .push_maybe(self.login.as_ref().map(|login| self.login_view(login)))
+            .into()
+    }
+
+    fn login_view<'a>(&self, login: &'a LoginPrompt) -> Element<'a, DownloadMessage> {
+        let prompt = match &login.realm {
+            Some(realm) => format!("Log in to {} ({}):", login.host, realm),
+            None => format!("Log in to {}:", login.host),
+        };
+        let log_in = (!login.username.trim().is_empty()).then_some(DownloadMessage::LogIn);
+        row![
+            text(prompt),
+            text_input("User name", &login.username)
+                .on_input(DownloadMessage::EditLoginUsername)
+                .width(150),
+            text_input("Password", &login.password)
+                .secure(true)
+                .on_input(DownloadMessage::EditLoginPassword)
+                .on_submit_maybe(log_in.clone())
+                .width(150),
+        ]
+        .push_maybe(self.is_own_host(&login.host).then(|| {
+            checkbox("Remember for this host", login.remember)
+                .on_toggle(DownloadMessage::RememberLogin)
+        }))
+        .push(button("log in").on_press_maybe(log_in))
+        .push(button("dismiss").on_press(DownloadMessage::DismissLogin))
+        .spacing(10)
+        .into()
+    }
+
+    /// Deletes the partial file. A finished download at the final path stays.
+    fn discard_partial(&self) {
+        if self.file_path.is_empty() {
+            return;
+        }
+        let part_path = naming::part_path(&self.file_path);
+        if let Err(e) = std::fs::remove_file(&part_path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("Couldn't remove {}: {}", part_path, e);
+            }
+        }
+    }
+
+    /// The login from the prompt if it's to be kept for its whole host,
+    /// which it always is for a host other than the download's own.
+    pub fn host_login(&self) -> Option<(String, Credentials)> {
+        let login = self.login.as_ref()?;
+        (login.remember || !self.is_own_host(&login.host)).then(|| {
+            (
+                login.host.clone(),
+                Credentials {
+                    username: login.username.clone(),
+                    password: login.password.clone(),
+                },
+            )
+        })
+    }
+
+    fn is_own_host(&self, host: &str) -> bool {
+        auth::host_of(&self.url).is_some_and(|own| own.eq_ignore_ascii_case(host))
+    }
+
+    /// Whether this download is holding one of the active slots.
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self.status,
+            DownloadStatus::InProgress { .. } | DownloadStatus::Verifying
+        )
+    }
+
+    /// `settings` fill in whatever this download doesn't choose for itself.
+    pub fn subscription(
+        &self,
+        settings: &Settings,
+    ) -> Subscription<(i64, Result<download::Progress, download::Error>)> {
+        let downloaded_bytes = match self.status {
+            DownloadStatus::InProgress {
+                downloaded_bytes, ..
+            } => downloaded_bytes,
+            // Every chunk is already complete, so this goes straight to hashing.
+            DownloadStatus::Verifying => 0,
+            _ => return Subscription::none(),
+        };
+
+        download::file(download::Job {
+            id: self.id,
+            url: self.url.clone(),
+            mirrors: self
+                .mirrors
+                .iter()
+                .map(|mirror| mirror.url.clone())
+                .collect(),
+            file_name: self.file_name.clone(),
+            headers: self.headers.clone(),
+            file_path: self.file_path.clone(),
+            total_size: self.total_size.map(|size| size as u64),
+            expected_size: self.expected_size,
+            chunks: self.resume_chunks(downloaded_bytes),
+            validators: self.validators.clone(),
+            supports_ranges: self.supports_ranges,
+            checksum: self.checksum.clone(),
+            pieces: self.pieces.clone(),
+            download_dir: self.download_dir.clone(),
+            settings: settings.clone(),
+            collision: self.collision_policy.unwrap_or(settings.collision_policy),
+            stop: self.stop.clone(),
+            throttle: self.throttle.clone(),
+        })
+    }
+
+    pub fn set_speed_limit(&mut self, limit: Option<u64>) {
+        self.speed_limit = limit;
+        self.throttle.set_rate(limit);
+    }
+
+    /// Rows saved before downloads were split into chunks only know how many
+    /// bytes arrived, which is a single chunk starting at zero.
+    fn resume_chunks(&self, downloaded_bytes: u64) -> Vec<Chunk> {
+        if !self.chunks.is_empty() || downloaded_bytes == 0 {
+            return self.chunks.clone();
+        }
+        vec![Chunk {
+            downloaded_bytes,
+            end_byte: self
+                .total_size
+                .filter(|total| *total > 0)
+                .map(|total| total as u64 - 1),
+            ..Chunk::default()
+        }]
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const KB: u64 = 1024;
+    const MB: u64 = KB * 1024;
+    const GB: u64 = MB * 1024;
+
+    if bytes >= GB {
+        format!("{:.2} GB", bytes as f64 / GB as f64)
+    } else if bytes >= MB {
+        format!("{:.2} MB", bytes as f64 / MB as f64)
+    } else if bytes >= KB {
+        format!("{:.2} KB", bytes as f64 / KB as f64)
+    } else {
+        format!("{} B", bytes)
+    }
+}
@@ -0,0 +1,236 @@
+use percent_encoding::percent_decode_str;
+use reqwest::header::{HeaderMap, CONTENT_DISPOSITION, CONTENT_TYPE};
+use reqwest::Url;
+
+/// Used when neither the headers nor the URL give us anything to go on.
+const FALLBACK_NAME: &str = "download";
+/// Most filesystems cap a name at 255 bytes.
+const MAX_NAME_LEN: usize = 255;
+/// Device names Windows won't let a file take, whatever its extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Appended to the name of a file while it is still being downloaded.
+const PART_EXTENSION: &str = "hedgehog-part";
+
+/// Where the data for `file_path` is written until it is complete.
+pub fn part_path(file_path: &str) -> String {
+    format!("{}.{}", file_path, PART_EXTENSION)
+}
+
+/// Works out what to call a download: `Content-Disposition` first, then the
+/// last segment of the final URL (after redirects), with an extension from
+/// `Content-Type` when the name doesn't have one.
+pub fn file_name(url: &Url, headers: &HeaderMap) -> String {
+    let name = headers
+        .get(CONTENT_DISPOSITION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(content_disposition_file_name)
+        .or_else(|| url_file_name(url))
+        .map(|name| sanitize(&name))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| FALLBACK_NAME.to_string());
+
+    let mime_extension = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(extension_for_mime);
+    match mime_extension {
+        Some(extension) if !has_extension(&name) => truncate(&format!("{}.{}", name, extension)),
+        _ => name,
+    }
+}
+
+/// The name the old engine used: whatever follows the last `/`.
+pub fn legacy_file_name(url: &str) -> String {
+    let name = sanitize(url.split('/').next_back().unwrap_or_default());
+    if name.is_empty() {
+        FALLBACK_NAME.to_string()
+    } else {
+        name
+    }
+}
+
+/// Pulls the file name out of a `Content-Disposition` header, preferring the
+/// RFC 5987 `filename*` form over plain `filename`.
+fn content_disposition_file_name(value: &str) -> Option<String> {
+    let params = parse_params(value);
+    let extended = params
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("filename*"))
+        .and_then(|(_, value)| decode_ext_value(value));
+
+    extended.or_else(|| {
+        params
+            .into_iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("filename"))
+            .map(|(_, value)| value)
+    })
+}
+
+/// Splits `attachment; key=value; key="quoted; value"` into key/value pairs,
+/// unescaping quoted strings. The disposition type itself is skipped.
+fn parse_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+
+    // Skip the disposition type.
+    for c in chars.by_ref() {
+        if c == ';' {
+            break;
+        }
+    }
+
+    loop {
+        let key: String = chars
+            .by_ref()
+            .take_while(|c| *c != '=')
+            .collect::<String>()
+            .trim()
+            .trim_start_matches(';')
+            .trim()
+            .to_string();
+        if key.is_empty() {
+            break;
+        }
+
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+            }
+        } else {
+            value = chars
+                .by_ref()
+                .take_while(|c| *c != ';')
+                .collect::<String>()
+                .trim()
+                .to_string();
+        }
+
+        params.push((key, value));
+    }
+
+    params
+}
+
+/// Decodes an RFC 5987 `charset'language'percent-encoded` value.
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?;
+    let bytes: Vec<u8> = percent_decode_str(encoded).collect();
+
+    if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(bytes).ok()
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        Some(bytes.into_iter().map(char::from).collect())
+    } else {
+        None
+    }
+}
+
+/// The last non-empty path segment, percent-decoded. Query strings and
+/// fragments never make it in because `Url` keeps them apart.
+fn url_file_name(url: &Url) -> Option<String> {
+    let segment = url.path_segments()?.rev().find(|s| !s.is_empty())?;
+    Some(percent_decode_str(segment).decode_utf8_lossy().into_owned())
+}
+
+fn extension_for_mime(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    let extension = match mime.as_str() {
+        "application/zip" => "zip",
+        "application/gzip" | "application/x-gzip" => "gz",
+        "application/x-tar" => "tar",
+        "application/x-bzip2" => "bz2",
+        "application/x-xz" => "xz",
+        "application/zstd" => "zst",
+        "application/x-7z-compressed" => "7z",
+        "application/vnd.rar" | "application/x-rar-compressed" => "rar",
+        "application/x-iso9660-image" => "iso",
+        "application/x-bittorrent" => "torrent",
+        "application/pdf" => "pdf",
+        "application/json" => "json",
+        "application/xml" | "text/xml" => "xml",
+        "application/vnd.debian.binary-package" => "deb",
+        "application/x-rpm" => "rpm",
+        "application/vnd.android.package-archive" => "apk",
+        "application/x-msdownload" => "exe",
+        "application/x-apple-diskimage" => "dmg",
+        "text/plain" => "txt",
+        "text/html" => "html",
+        "text/csv" => "csv",
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "audio/mpeg" => "mp3",
+        "audio/ogg" => "ogg",
+        "audio/flac" => "flac",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "video/x-matroska" => "mkv",
+        _ => return None,
+    };
+    Some(extension)
+}
+
+fn has_extension(name: &str) -> bool {
+    name.rsplit_once('.')
+        .is_some_and(|(stem, extension)| !stem.is_empty() && !extension.is_empty())
+}
+
+/// Makes a name safe to create on Linux, macOS and Windows alike: no path
+/// separators, control or reserved characters, no trailing dots or spaces
+/// and no reserved device names.
+pub fn sanitize(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let cleaned = cleaned.trim().trim_matches('.').trim();
+
+    let stem = cleaned.split('.').next().unwrap_or_default();
+    let reserved = RESERVED_NAMES.contains(&stem.to_ascii_uppercase().as_str());
+    if reserved {
+        return truncate(&format!("_{}", cleaned));
+    }
+    truncate(cleaned)
+}
+
+/// Shortens a name to `MAX_NAME_LEN` bytes, keeping its extension.
+fn truncate(name: &str) -> String {
+    if name.len() <= MAX_NAME_LEN {
+        return name.to_string();
+    }
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if extension.len() < 16 => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    let mut end = MAX_NAME_LEN - extension.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], extension)
+}
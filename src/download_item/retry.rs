@@ -0,0 +1,82 @@
+use rand::Rng;
+use std::time::{Duration, SystemTime};
+
+use super::download::Error;
+
+/// Backoff never grows past this, however many attempts have failed.
+const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// How a chunk recovers from transient network and server errors. Every
+/// retry picks up from the bytes that were already written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub jitter: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            jitter: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Reads `HEDGEHOG_RETRY_MAX_ATTEMPTS`, `HEDGEHOG_RETRY_BASE_DELAY_MS` and
+    /// `HEDGEHOG_RETRY_JITTER_MS`, keeping the default for anything unset.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let var = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+
+        Self {
+            max_attempts: var("HEDGEHOG_RETRY_MAX_ATTEMPTS")
+                .map(|v| v as u32)
+                .unwrap_or(default.max_attempts),
+            base_delay: var("HEDGEHOG_RETRY_BASE_DELAY_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.base_delay),
+            jitter: var("HEDGEHOG_RETRY_JITTER_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.jitter),
+        }
+    }
+
+    /// How long to wait before `attempt` (starting at 1), or `None` when the
+    /// error isn't worth retrying or the attempts are used up.
+    pub fn delay(&self, attempt: u32, error: &Error) -> Option<Duration> {
+        if attempt > self.max_attempts || !error.is_retryable() {
+            return None;
+        }
+
+        if let Some(retry_after) = error.retry_after() {
+            return Some(retry_after.min(MAX_DELAY));
+        }
+
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(MAX_DELAY);
+        let jitter = if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rand::thread_rng().gen_range(Duration::ZERO..=self.jitter)
+        };
+        Some(backoff + jitter)
+    }
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    httpdate::parse_http_date(value)
+        .ok()
+        .map(|date| date.duration_since(SystemTime::now()).unwrap_or_default())
+}
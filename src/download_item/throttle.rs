@@ -0,0 +1,87 @@
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// A token bucket measured in bytes. Writers take what they are about to
+/// write and sleep off any deficit, so the limit can change while transfers
+/// are running and the next write picks it up.
+#[derive(Debug, Default)]
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    /// Bytes per second, `None` for unlimited.
+    rate: Option<u64>,
+    /// Can go negative: that's bytes already written on credit.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Default for BucketState {
+    fn default() -> Self {
+        Self {
+            rate: None,
+            tokens: 0.0,
+            refilled_at: Instant::now(),
+        }
+    }
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        let bucket = Self::default();
+        bucket.set_rate(rate);
+        bucket
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.rate = rate.filter(|rate| *rate > 0);
+        // Start the new rate with an empty bucket rather than a burst.
+        state.tokens = 0.0;
+        state.refilled_at = Instant::now();
+    }
+
+    /// Takes `bytes` from the bucket, waiting until they've been paid for.
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let Some(rate) = state.rate else {
+                return;
+            };
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+            // At most one second's worth of burst builds up while idle.
+            state.tokens = (state.tokens + elapsed * rate as f64).min(rate as f64);
+            state.refilled_at = now;
+            state.tokens -= bytes as f64;
+
+            if state.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-state.tokens / rate as f64)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// The limit every download shares, on top of its own.
+pub fn global() -> &'static TokenBucket {
+    static GLOBAL: OnceLock<TokenBucket> = OnceLock::new();
+    GLOBAL.get_or_init(TokenBucket::default)
+}
+
+/// Parses a limit typed in KB/s. Empty means unlimited, `None` means the
+/// input isn't a number.
+pub fn parse_limit(input: &str) -> Option<Option<u64>> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Some(None);
+    }
+    input
+        .parse::<u64>()
+        .ok()
+        .map(|kbps| (kbps > 0).then_some(kbps * 1024))
+}
@@ -1,47 +1,314 @@
+use download_item::auth::{self, Credentials};
+use download_item::checksum::Checksum;
+use download_item::metalink::{self, MetalinkFile};
+use download_item::mirrors::Mirror;
+use download_item::throttle;
 use download_item::{download, DownloadItem, DownloadMessage, DownloadStatus};
 use iced::{
-    clipboard,
-    widget::{button, column, container},
-    Element, Task,
+    clipboard, event, mouse,
+    widget::{button, column, container, mouse_area, row, text, text_input},
+    window, Element, Event, Task,
 };
 use rusqlite::{Connection, Result};
+use scheduler::{Schedule, ScheduleEffect};
+use settings::Settings;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+use torrent::metainfo::{self, Metainfo};
+use torrent::{TorrentItem, TorrentMessage, TorrentStatus};
 use ui::modal::modal;
+use ui::schedule_editor::{ScheduleEditor, ScheduleEditorMessage};
+use ui::settings_editor::{self, SettingsMessage};
 use ui::url_input::{UrlInput, UrlInputMessage};
+use utils::proxy::{self, ProxyRules};
 
 mod db;
 mod download_item;
+mod scheduler;
+mod settings;
+mod torrent;
 mod ui;
 mod utils;
 
+/// How many downloads run at once unless the user picks another number.
+const DEFAULT_MAX_ACTIVE: usize = 3;
+/// Schedules work in minutes, so checking twice a minute is plenty.
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(30);
+
 #[derive(Default)]
 struct AppState {
     download_items: Vec<DownloadItem>,
+    torrents: Vec<TorrentItem>,
     url_input: UrlInput,
     show_modal: bool,
+    /// Speed cap shared by all downloads, in bytes per second.
+    global_speed_limit: Option<u64>,
+    /// Queued downloads only start while fewer than this many are running.
+    max_active: usize,
+    settings: Settings,
+    show_settings: bool,
+    /// Index of the download being dragged to a new place in the queue.
+    dragging: Option<usize>,
+    schedules: Vec<Schedule>,
+    schedule_effect: ScheduleEffect,
+    schedule_editor: ScheduleEditor,
+    show_schedules: bool,
+    /// Downloads the scheduler paused, which go back in the queue once stopped.
+    held_by_schedule: HashSet<i64>,
+    /// The same for torrents, whose ids come from a different list.
+    torrents_held_by_schedule: HashSet<i64>,
 }
 
 #[derive(Debug, Clone)]
 enum AppMessage {
     UrlInput(UrlInputMessage),
     DownloadItem(usize, download_item::DownloadMessage),
+    /// Progress from a running transfer, addressed by download id because
+    /// list positions change when the queue is reordered.
+    DownloadProgress(i64, download_item::DownloadMessage),
     ShowModal,
     HideModal,
+    SetGlobalSpeedLimit(String),
+    SetMaxActive(String),
+    DragStart(usize),
+    DragOver(usize),
+    DragEnd,
+    ToggleSchedules,
+    ToggleSettings,
+    Settings(SettingsMessage),
+    ScheduleEditor(ScheduleEditorMessage),
+    SchedulerTick,
+    /// A metalink from the add dialog was read, one entry per file.
+    MetalinkLoaded(Result<Vec<MetalinkFile>, String>),
+    /// A `.torrent` from the add dialog or a drop was read.
+    TorrentLoaded(Result<Metainfo, String>),
+    Torrent(usize, TorrentMessage),
+    /// News from a running torrent, addressed by id like `DownloadProgress`.
+    TorrentProgress(i64, TorrentMessage),
+    FileDropped(PathBuf),
 }
 
 impl AppState {
     pub fn new(conn: &Connection) -> Result<Self> {
+        // Whatever was running goes back through the queue, so the limit holds.
         let mut downloads = db::load_downloads(conn)?;
         downloads.iter_mut().for_each(|item| {
             if matches!(item.status, DownloadStatus::InProgress { .. }) {
-                let _ = item.update(DownloadMessage::StartDownload);
+                let _ = item.update(DownloadMessage::QueueDownload);
+            }
+        });
+
+        let mut torrents = db::load_torrents(conn)?;
+        torrents.iter_mut().for_each(|item| {
+            if item.is_active() {
+                item.status = TorrentStatus::Queued;
             }
         });
 
-        Ok(Self {
+        let global_speed_limit = db::load_setting(conn, "global_speed_limit")?
+            .and_then(|limit| limit.parse::<u64>().ok());
+        throttle::global().set_rate(global_speed_limit);
+        for (host, credentials) in db::load_host_credentials(conn)? {
+            auth::remember(&host, Some(credentials));
+        }
+
+        let settings = Settings::load(conn)?;
+        match ProxyRules::from_settings(&settings) {
+            Ok(rules) => proxy::set(rules),
+            Err(e) => log::error!("Ignoring proxy settings: {}", e),
+        }
+
+        let max_active = db::load_setting(conn, "max_active_downloads")?
+            .and_then(|max| max.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_MAX_ACTIVE);
+
+        let mut state = Self {
             download_items: downloads,
+            torrents,
             url_input: UrlInput::default(),
             show_modal: false,
-        })
+            global_speed_limit,
+            max_active,
+            settings,
+            show_settings: false,
+            dragging: None,
+            schedules: db::load_schedules(conn)?,
+            schedule_effect: ScheduleEffect::default(),
+            schedule_editor: ScheduleEditor::default(),
+            show_schedules: false,
+            held_by_schedule: HashSet::new(),
+            torrents_held_by_schedule: HashSet::new(),
+        };
+        state.apply_schedules();
+        state.fill_slots();
+        Ok(state)
+    }
+
+    /// Brings the queue and the global speed limit in line with whatever
+    /// schedule windows are open right now.
+    fn apply_schedules(&mut self) {
+        self.schedule_effect = scheduler::evaluate_now(&self.schedules);
+        throttle::global().set_rate(self.schedule_effect.speed_limit.or(self.global_speed_limit));
+
+        if self.schedule_effect.downloads_allowed {
+            self.fill_slots();
+            return;
+        }
+
+        for item in &mut self.download_items {
+            if !matches!(item.status, DownloadStatus::InProgress { .. }) || item.stop.is_cancelled()
+            {
+                continue;
+            }
+            // Pausing would throw away what it has, which only the user decides.
+            if item.supports_ranges == Some(false) {
+                item.notice =
+                    Some("Server can't resume, left running through the pause".to_string());
+                continue;
+            }
+            let _ = item.update(DownloadMessage::PauseDownload);
+            self.held_by_schedule.insert(item.id);
+        }
+        for item in &mut self.torrents {
+            if item.is_active() && !item.stop.is_cancelled() {
+                let _ = item.update(TorrentMessage::Stop);
+                self.torrents_held_by_schedule.insert(item.id);
+            }
+        }
+    }
+
+    /// Starts queued downloads, highest priority first, while slots are free.
+    fn fill_slots(&mut self) {
+        if !self.schedule_effect.downloads_allowed {
+            return;
+        }
+
+        let active = self
+            .download_items
+            .iter()
+            .filter(|item| item.is_active())
+            .count()
+            + self.torrents.iter().filter(|item| item.is_active()).count();
+        let mut free = self.max_active.saturating_sub(active);
+
+        let conn = db::open();
+        self.download_items
+            .iter_mut()
+            .filter(|item| item.status == DownloadStatus::Queued)
+            .take(free)
+            .for_each(|item| {
+                let _ = item.update(DownloadMessage::StartDownload);
+                free -= 1;
+                if let Ok(conn) = &conn {
+                    let _ = db::save_download(conn, item);
+                }
+            });
+        // Torrents share the slots, after the downloads.
+        self.torrents
+            .iter_mut()
+            .filter(|item| item.status == TorrentStatus::Queued)
+            .take(free)
+            .for_each(|item| {
+                let _ = item.update(TorrentMessage::Start);
+                if let Ok(conn) = &conn {
+                    let _ = db::save_torrent(conn, item);
+                }
+            });
+    }
+
+    /// Puts a new download at the end of the queue with the folder and
+    /// collision policy picked in the add dialog.
+    fn add_download(&mut self, mut new_item: DownloadItem) {
+        // Ids come from the clock, so several added at once can clash.
+        while self
+            .download_items
+            .iter()
+            .any(|item| item.id == new_item.id)
+        {
+            new_item.id += 1;
+        }
+        new_item.collision_policy = self.url_input.collision_policy;
+        new_item.download_dir = Some(self.url_input.download_dir.trim())
+            .filter(|dir| !dir.is_empty())
+            .map(str::to_string);
+        new_item.headers = self.url_input.request_headers();
+        if self.url_input.remember_login {
+            let host = auth::host_of(self.url_input.value.trim());
+            if let (Some(host), Some(credentials)) = (host, new_item.headers.credentials.take()) {
+                remember_login(&host, credentials);
+            }
+        }
+        new_item.priority = self
+            .download_items
+            .iter()
+            .map(|item| item.priority + 1)
+            .max()
+            .unwrap_or(0);
+        let _ = new_item.update(DownloadMessage::QueueDownload);
+
+        // Save to database
+        if let Ok(conn) = db::open() {
+            let _ = db::save_download(&conn, &new_item);
+        }
+
+        self.download_items.push(new_item);
+    }
+
+    /// Adds a parsed torrent at the end of the list, unless it's already on it.
+    fn add_torrent(&mut self, metainfo: Metainfo) -> Result<(), String> {
+        if let Some(existing) = self
+            .torrents
+            .iter()
+            .find(|item| item.metainfo.info_hash == metainfo.info_hash)
+        {
+            return Err(format!("{} is already on the list", existing.metainfo.name));
+        }
+
+        let mut new_item = TorrentItem::new(metainfo);
+        while self.torrents.iter().any(|item| item.id == new_item.id) {
+            new_item.id += 1;
+        }
+        new_item.download_dir = Some(self.url_input.download_dir.trim())
+            .filter(|dir| !dir.is_empty())
+            .map(str::to_string);
+        new_item.priority = self
+            .torrents
+            .iter()
+            .map(|item| item.priority + 1)
+            .max()
+            .unwrap_or(0);
+
+        if let Ok(conn) = db::open() {
+            let _ = db::save_torrent(&conn, &new_item);
+        }
+
+        self.torrents.push(new_item);
+        Ok(())
+    }
+
+    /// Reads a torrent from whatever is in the add dialog, which stays open
+    /// to show an error.
+    fn load_torrent(&mut self) -> Task<AppMessage> {
+        self.url_input.loading = true;
+        self.url_input.error = None;
+        let location = self.url_input.value.trim().to_string();
+        let headers = self.url_input.request_headers();
+        Task::perform(
+            async move { metainfo::load(&location, &headers).await },
+            AppMessage::TorrentLoaded,
+        )
+    }
+
+    /// Queue order is list order; priorities are rewritten to match it.
+    fn save_priorities(&mut self) {
+        let conn = db::open();
+        for (priority, item) in self.download_items.iter_mut().enumerate() {
+            item.priority = priority as i64;
+            if let Ok(conn) = &conn {
+                let _ = db::save_download(conn, item);
+            }
+        }
     }
 
     fn update(&mut self, message: AppMessage) -> Task<AppMessage> {
@@ -53,51 +320,327 @@ impl AppState {
             }
             AppMessage::HideModal => {
                 self.show_modal = false;
-                self.url_input.value.clear();
+                self.url_input.clear();
+                Task::none()
+            }
+            AppMessage::SetGlobalSpeedLimit(input) => {
+                if let Some(limit) = throttle::parse_limit(&input) {
+                    self.global_speed_limit = limit;
+                    // A throttling schedule window takes precedence while it's open.
+                    throttle::global().set_rate(self.schedule_effect.speed_limit.or(limit));
+
+                    if let Ok(conn) = db::open() {
+                        let value = limit.map(|limit| limit.to_string());
+                        let _ = db::save_setting(&conn, "global_speed_limit", value.as_deref());
+                    }
+                }
+                Task::none()
+            }
+            AppMessage::SetMaxActive(input) => {
+                if let Some(max_active) = input.trim().parse::<usize>().ok().filter(|max| *max > 0)
+                {
+                    self.max_active = max_active;
+                    if let Ok(conn) = db::open() {
+                        let value = max_active.to_string();
+                        let _ = db::save_setting(&conn, "max_active_downloads", Some(&value));
+                    }
+                    self.fill_slots();
+                }
+                Task::none()
+            }
+            AppMessage::DragStart(index) => {
+                self.dragging = Some(index);
+                Task::none()
+            }
+            AppMessage::DragOver(index) => {
+                if let Some(from) = self.dragging.filter(|from| *from != index) {
+                    let item = self.download_items.remove(from);
+                    self.download_items.insert(index, item);
+                    self.dragging = Some(index);
+                }
+                Task::none()
+            }
+            AppMessage::DragEnd => {
+                if self.dragging.take().is_some() {
+                    self.save_priorities();
+                    self.fill_slots();
+                }
+                Task::none()
+            }
+            AppMessage::ToggleSchedules => {
+                self.show_schedules = !self.show_schedules;
+                Task::none()
+            }
+            AppMessage::ToggleSettings => {
+                self.show_settings = !self.show_settings;
+                Task::none()
+            }
+            AppMessage::Settings(settings_msg) => {
+                settings_editor::update(&mut self.settings, settings_msg);
+                // Half-typed proxy settings leave the last good ones in place.
+                if let Ok(rules) = ProxyRules::from_settings(&self.settings) {
+                    proxy::set(rules);
+                }
+                if let Ok(conn) = db::open() {
+                    let _ = self.settings.save(&conn);
+                }
+                Task::none()
+            }
+            AppMessage::ScheduleEditor(ScheduleEditorMessage::Add) => {
+                if let (Some(mut schedule), Ok(conn)) =
+                    (self.schedule_editor.schedule(), db::open())
+                {
+                    if let Ok(id) = db::insert_schedule(&conn, &schedule) {
+                        schedule.id = id;
+                        self.schedules.push(schedule);
+                        self.apply_schedules();
+                    }
+                }
+                Task::none()
+            }
+            AppMessage::ScheduleEditor(ScheduleEditorMessage::Remove(id)) => {
+                if let Ok(conn) = db::open() {
+                    let _ = db::delete_schedule(&conn, id);
+                }
+                self.schedules.retain(|schedule| schedule.id != id);
+                self.apply_schedules();
+                Task::none()
+            }
+            AppMessage::ScheduleEditor(editor_msg) => {
+                self.schedule_editor.update(editor_msg);
+                Task::none()
+            }
+            AppMessage::SchedulerTick => {
+                self.apply_schedules();
                 Task::none()
             }
             AppMessage::UrlInput(url_msg) => match url_msg {
+                UrlInputMessage::Add if self.url_input.is_metalink() => {
+                    self.url_input.loading = true;
+                    self.url_input.error = None;
+                    let location = self.url_input.value.trim().to_string();
+                    let headers = self.url_input.request_headers();
+                    Task::perform(
+                        async move { metalink::load(&location, &headers).await },
+                        AppMessage::MetalinkLoaded,
+                    )
+                }
+                UrlInputMessage::Add if self.url_input.is_torrent() => self.load_torrent(),
                 UrlInputMessage::Add => {
                     let mut new_item = DownloadItem::new(self.url_input.value.clone());
-                    let _ = new_item.update(download_item::DownloadMessage::StartDownload);
-
-                    // Save to database
-                    if let Ok(conn) = Connection::open("downloads.db") {
-                        let _ = db::save_download(&conn, &new_item);
-                    }
-
-                    self.download_items.push(new_item);
-                    self.url_input.value.clear();
+                    new_item.checksum = Checksum::parse(&self.url_input.checksum);
+                    new_item.mirrors = self
+                        .url_input
+                        .mirror_urls()
+                        .into_iter()
+                        .map(Mirror::new)
+                        .collect();
+                    self.add_download(new_item);
+                    self.url_input.clear();
                     self.show_modal = false;
+                    self.fill_slots();
                     Task::none()
                 }
                 _ => self.url_input.update(url_msg).map(AppMessage::UrlInput),
             },
-            AppMessage::DownloadItem(index, download_message) => {
-                if let Some(item) = self.download_items.get_mut(index) {
-                    let _ = item.update(download_message);
-                    // Update database when download status changes
-                    if let Ok(conn) = Connection::open("downloads.db") {
-                        let _ = db::save_download(&conn, item);
+            AppMessage::MetalinkLoaded(Ok(files)) => {
+                for file in files {
+                    self.add_download(DownloadItem::from_metalink(file));
+                }
+                self.url_input.clear();
+                self.show_modal = false;
+                self.fill_slots();
+                Task::none()
+            }
+            AppMessage::MetalinkLoaded(Err(e)) => {
+                self.url_input.loading = false;
+                self.url_input.error = Some(e);
+                Task::none()
+            }
+            AppMessage::TorrentLoaded(Ok(metainfo)) => {
+                match self.add_torrent(metainfo) {
+                    Ok(()) => {
+                        self.url_input.clear();
+                        self.show_modal = false;
+                    }
+                    Err(e) => {
+                        self.url_input.loading = false;
+                        self.url_input.error = Some(e);
+                    }
+                }
+                Task::none()
+            }
+            AppMessage::TorrentLoaded(Err(e)) => {
+                self.url_input.loading = false;
+                self.url_input.error = Some(e);
+                Task::none()
+            }
+            AppMessage::FileDropped(path) => {
+                let path = path.to_string_lossy().to_string();
+                if !metainfo::is_torrent(&path, None) {
+                    log::info!("Ignoring dropped file {}", path);
+                    return Task::none();
+                }
+                self.url_input.clear();
+                self.url_input.value = path;
+                self.show_modal = true;
+                self.load_torrent()
+            }
+            AppMessage::Torrent(index, TorrentMessage::Remove) => {
+                if index < self.torrents.len() {
+                    let mut item = self.torrents.remove(index);
+                    let _ = item.update(TorrentMessage::Remove);
+                    self.torrents_held_by_schedule.remove(&item.id);
+                    if let Ok(conn) = db::open() {
+                        let _ = db::delete_torrent(&conn, item.id);
                     }
+                    self.fill_slots();
                 }
                 Task::none()
             }
+            AppMessage::Torrent(index, torrent_message) => {
+                self.update_torrent(index, torrent_message)
+            }
+            AppMessage::TorrentProgress(id, torrent_message) => {
+                match self.torrents.iter().position(|item| item.id == id) {
+                    Some(index) => self.update_torrent(index, torrent_message),
+                    None => Task::none(),
+                }
+            }
+            AppMessage::DownloadItem(index, DownloadMessage::RemoveDownload) => {
+                if index < self.download_items.len() {
+                    let mut item = self.download_items.remove(index);
+                    let _ = item.update(DownloadMessage::RemoveDownload);
+                    self.held_by_schedule.remove(&item.id);
+                    if let Ok(conn) = db::open() {
+                        let _ = db::delete_download(&conn, item.id);
+                    }
+                    self.fill_slots();
+                }
+                Task::none()
+            }
+            AppMessage::DownloadItem(index, DownloadMessage::LogIn) => {
+                let host_login = self
+                    .download_items
+                    .get(index)
+                    .and_then(DownloadItem::host_login);
+                if let Some((host, credentials)) = host_login {
+                    remember_login(&host, credentials);
+                }
+                self.update_item(index, DownloadMessage::LogIn)
+            }
+            AppMessage::DownloadItem(index, download_message) => {
+                // Starting by hand still waits for a free slot.
+                let download_message = match download_message {
+                    DownloadMessage::StartDownload => DownloadMessage::QueueDownload,
+                    message => message,
+                };
+                self.update_item(index, download_message)
+            }
+            AppMessage::DownloadProgress(id, download_message) => {
+                match self.download_items.iter().position(|item| item.id == id) {
+                    Some(index) => self.update_item(index, download_message),
+                    None => Task::none(),
+                }
+            }
+        }
+    }
+
+    fn update_item(&mut self, index: usize, message: DownloadMessage) -> Task<AppMessage> {
+        if let Some(item) = self.download_items.get_mut(index) {
+            let paused = matches!(message, DownloadMessage::Paused(..));
+            let _ = item.update(message);
+            if paused && self.held_by_schedule.remove(&item.id) {
+                let _ = item.update(DownloadMessage::QueueDownload);
+            }
+            // Update database when download status changes
+            if let Ok(conn) = db::open() {
+                let _ = db::save_download(&conn, item);
+            }
         }
+        self.fill_slots();
+        Task::none()
     }
 
-    fn view(&self) -> Element<AppMessage> {
-        let body = column![
+    fn update_torrent(&mut self, index: usize, message: TorrentMessage) -> Task<AppMessage> {
+        if let Some(item) = self.torrents.get_mut(index) {
+            let stopped = matches!(message, TorrentMessage::Stopped(..));
+            let _ = item.update(message);
+            if stopped && self.torrents_held_by_schedule.remove(&item.id) {
+                let _ = item.update(TorrentMessage::Queue);
+            }
+            if let Ok(conn) = db::open() {
+                let _ = db::save_torrent(&conn, item);
+            }
+        }
+        self.fill_slots();
+        Task::none()
+    }
+
+    fn view(&self) -> Element<'_, AppMessage> {
+        let global_speed_limit = self
+            .global_speed_limit
+            .map(|limit| (limit / 1024).to_string())
+            .unwrap_or_default();
+
+        let header = row![
             button("Add Download").on_press(AppMessage::ShowModal),
+            text_input("Global KB/s limit", &global_speed_limit)
+                .on_input(AppMessage::SetGlobalSpeedLimit)
+                .width(150),
+            text("Max active:"),
+            text_input("Max active", &self.max_active.to_string())
+                .on_input(AppMessage::SetMaxActive)
+                .width(60),
+            button("Schedules").on_press(AppMessage::ToggleSchedules),
+            button("Settings").on_press(AppMessage::ToggleSettings),
+        ]
+        .spacing(10);
+
+        let mut body = column![header].spacing(10);
+        if self.show_schedules {
+            body = body.push(
+                self.schedule_editor
+                    .view(&self.schedules)
+                    .map(AppMessage::ScheduleEditor),
+            );
+        }
+        if self.show_settings {
+            body = body.push(settings_editor::view(&self.settings).map(AppMessage::Settings));
+        }
+        if !self.schedule_effect.downloads_allowed {
+            body = body.push(text(
+                "Downloads are on hold until the next scheduled window",
+            ));
+        }
+
+        let body = body.push(
+            column(self.download_items.iter().enumerate().map(|(i, item)| {
+                let handle = mouse_area(text(if self.dragging == Some(i) {
+                    "▶"
+                } else {
+                    "≡"
+                }))
+                .on_press(AppMessage::DragStart(i))
+                .interaction(mouse::Interaction::Grab);
+                let entry = row![
+                    handle,
+                    item.view().map(move |msg| AppMessage::DownloadItem(i, msg))
+                ]
+                .spacing(10);
+                mouse_area(entry).on_enter(AppMessage::DragOver(i)).into()
+            }))
+            .spacing(10),
+        );
+        let body = body.push(
             column(
-                self.download_items
+                self.torrents
                     .iter()
                     .enumerate()
-                    .map(|(i, item)| item.view().map(move |msg| AppMessage::DownloadItem(i, msg)))
+                    .map(|(i, item)| item.view().map(move |msg| AppMessage::Torrent(i, msg))),
             )
             .spacing(10),
-        ]
-        .spacing(10);
+        );
 
         if self.show_modal {
             let url_input = container(self.url_input.view().map(AppMessage::UrlInput));
@@ -108,21 +651,118 @@ impl AppState {
     }
 
     pub fn subscription(&self) -> iced::Subscription<AppMessage> {
-        iced::Subscription::batch(self.download_items.iter().enumerate().map(|(i, item)| {
-            let download_sub = item.subscription();
-            download_sub.with(i).map(|(i, progress)| {
-                let msg = match progress.1 {
-                    Ok(download::Progress::Advanced(progress, bytes)) => {
-                        DownloadMessage::UpdateProgress(progress, bytes)
+        let downloads = self.download_items.iter().map(|item| {
+            item.subscription(&self.settings).map(|(id, progress)| {
+                let msg = match progress {
+                    Ok(download::Progress::Started {
+                        file_path,
+                        total_size,
+                        chunks,
+                        validators,
+                        supports_ranges,
+                    }) => DownloadMessage::Prepared {
+                        file_path,
+                        total_size,
+                        chunks,
+                        validators,
+                        supports_ranges,
+                    },
+                    Ok(download::Progress::Advanced(progress, bytes, chunks)) => {
+                        DownloadMessage::UpdateProgress(progress, bytes, chunks)
+                    }
+                    Ok(download::Progress::Paused(progress, bytes, chunks)) => {
+                        DownloadMessage::Paused(progress, bytes, chunks)
+                    }
+                    Ok(download::Progress::Retrying(attempt, max_attempts)) => {
+                        DownloadMessage::Retrying(attempt, max_attempts)
                     }
+                    Ok(download::Progress::Verifying) => DownloadMessage::Verifying,
                     Ok(download::Progress::Finished) => DownloadMessage::CompleteDownload,
+                    Ok(download::Progress::Changed { old_size, new_size }) => {
+                        DownloadMessage::RemoteChanged(old_size, new_size)
+                    }
+                    Ok(download::Progress::Restarted) => DownloadMessage::Restarted,
+                    Ok(download::Progress::Skipped(file_path)) => {
+                        DownloadMessage::Skipped(file_path)
+                    }
+                    Err(download::Error::ChecksumMismatch { expected, actual }) => {
+                        DownloadMessage::ChecksumMismatch(format!(
+                            "expected {}, got {}",
+                            expected, actual
+                        ))
+                    }
+                    Err(download::Error::NotResumable) => DownloadMessage::NotResumable,
+                    Err(download::Error::Unauthorized { host, realm }) => {
+                        DownloadMessage::LoginRequired(host, realm)
+                    }
                     Err(e) => DownloadMessage::FailDownload(e.to_string()),
-                    _ => DownloadMessage::UpdateProgress(0.0, 0),
                 };
-                AppMessage::DownloadItem(i, msg)
+                AppMessage::DownloadProgress(id, msg)
+            })
+        });
+
+        let scheduler = if self.schedules.is_empty() {
+            iced::Subscription::none()
+        } else {
+            iced::time::every(SCHEDULER_INTERVAL).map(|_| AppMessage::SchedulerTick)
+        };
+
+        // The release can land anywhere, not just on a download.
+        let drag_end = if self.dragging.is_some() {
+            event::listen_with(|event, _status, _window| match event {
+                Event::Mouse(mouse::Event::ButtonReleased(mouse::Button::Left)) => {
+                    Some(AppMessage::DragEnd)
+                }
+                _ => None,
+            })
+        } else {
+            iced::Subscription::none()
+        };
+
+        let file_drops = event::listen_with(|event, _status, _window| match event {
+            Event::Window(window::Event::FileDropped(path)) => Some(AppMessage::FileDropped(path)),
+            _ => None,
+        });
+
+        let torrents = self.torrents.iter().map(|item| {
+            item.subscription(&self.settings).map(|(id, progress)| {
+                let msg = match progress {
+                    Ok(torrent::download::Progress::Checking(progress)) => {
+                        TorrentMessage::Checking(progress)
+                    }
+                    Ok(torrent::download::Progress::Advanced(progress, bytes, peers)) => {
+                        TorrentMessage::Progress(progress, bytes, peers)
+                    }
+                    Ok(torrent::download::Progress::Tracker(status)) => {
+                        TorrentMessage::Tracker(status)
+                    }
+                    Ok(torrent::download::Progress::PieceFailed(piece, banned)) => {
+                        TorrentMessage::PieceFailed(piece, banned)
+                    }
+                    Ok(torrent::download::Progress::Stopped(bytes)) => {
+                        TorrentMessage::Stopped(bytes)
+                    }
+                    Ok(torrent::download::Progress::Finished) => TorrentMessage::Completed,
+                    Err(e) => TorrentMessage::Failed(e.to_string()),
+                };
+                AppMessage::TorrentProgress(id, msg)
             })
-        }))
+        });
+
+        iced::Subscription::batch(
+            downloads
+                .chain(torrents)
+                .chain([scheduler, drag_end, file_drops]),
+        )
+    }
+}
+
+/// Uses a login for every download from `host`, now and after a restart.
+fn remember_login(host: &str, credentials: Credentials) {
+    if let Ok(conn) = db::open() {
+        let _ = db::save_host_credentials(&conn, host, Some(&credentials));
     }
+    auth::remember(host, Some(credentials));
 }
 
 fn main() {
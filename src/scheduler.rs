@@ -0,0 +1,128 @@
+use chrono::{Datelike, Local, Timelike};
+use std::fmt::{self, Display};
+
+pub const DAY_NAMES: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScheduleAction {
+    /// Downloads only run inside windows like this one.
+    Allow,
+    /// Downloads are held for the length of the window.
+    Pause,
+    /// Overrides the global speed limit (bytes per second) inside the window.
+    Throttle(u64),
+}
+
+impl Display for ScheduleAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleAction::Allow => write!(f, "Allow"),
+            ScheduleAction::Pause => write!(f, "Pause"),
+            ScheduleAction::Throttle(limit) => write!(f, "Throttle to {} KB/s", limit / 1024),
+        }
+    }
+}
+
+/// A weekly time window, e.g. weekdays 01:00–07:00. Windows that end
+/// earlier than they start run past midnight into the next day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub id: i64,
+    /// Bit 0 is Monday, bit 6 is Sunday.
+    pub days: u8,
+    /// Minutes since midnight.
+    pub start: u16,
+    pub end: u16,
+    pub action: ScheduleAction,
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = DAY_NAMES
+            .iter()
+            .enumerate()
+            .filter(|(day, _)| self.days & (1 << day) != 0)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(
+            f,
+            "{} {}–{}: {}",
+            days,
+            format_time(self.start),
+            format_time(self.end),
+            self.action
+        )
+    }
+}
+
+impl Schedule {
+    /// `day` counts from Monday = 0, `minute` from midnight.
+    pub fn is_active(&self, day: u32, minute: u16) -> bool {
+        let runs_on = |day: u32| self.days & (1 << (day % 7)) != 0;
+
+        if self.start == self.end {
+            runs_on(day)
+        } else if self.start < self.end {
+            runs_on(day) && (self.start..self.end).contains(&minute)
+        } else {
+            (runs_on(day) && minute >= self.start) || (runs_on(day + 6) && minute < self.end)
+        }
+    }
+}
+
+/// What the schedules add up to at a given moment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScheduleEffect {
+    pub downloads_allowed: bool,
+    /// Speed limit imposed by a `Throttle` window, replacing the global one.
+    pub speed_limit: Option<u64>,
+}
+
+impl Default for ScheduleEffect {
+    fn default() -> Self {
+        Self {
+            downloads_allowed: true,
+            speed_limit: None,
+        }
+    }
+}
+
+pub fn evaluate(schedules: &[Schedule], day: u32, minute: u16) -> ScheduleEffect {
+    let active = || schedules.iter().filter(|s| s.is_active(day, minute));
+
+    let has_allow_windows = schedules.iter().any(|s| s.action == ScheduleAction::Allow);
+    let in_allow_window = active().any(|s| s.action == ScheduleAction::Allow);
+    let in_pause_window = active().any(|s| s.action == ScheduleAction::Pause);
+
+    // With several throttles overlapping, the strictest one wins.
+    let speed_limit = active()
+        .filter_map(|s| match s.action {
+            ScheduleAction::Throttle(limit) => Some(limit),
+            _ => None,
+        })
+        .min();
+
+    ScheduleEffect {
+        downloads_allowed: (!has_allow_windows || in_allow_window) && !in_pause_window,
+        speed_limit,
+    }
+}
+
+pub fn evaluate_now(schedules: &[Schedule]) -> ScheduleEffect {
+    let now = Local::now();
+    let minute = (now.hour() * 60 + now.minute()) as u16;
+    evaluate(schedules, now.weekday().num_days_from_monday(), minute)
+}
+
+/// Parses `HH:MM` into minutes since midnight.
+pub fn parse_time(input: &str) -> Option<u16> {
+    let (hours, minutes) = input.trim().split_once(':')?;
+    let hours = hours.parse::<u16>().ok().filter(|h| *h < 24)?;
+    let minutes = minutes.parse::<u16>().ok().filter(|m| *m < 60)?;
+    Some(hours * 60 + minutes)
+}
+
+pub fn format_time(minute: u16) -> String {
+    format!("{:02}:{:02}", minute / 60, minute % 60)
+}
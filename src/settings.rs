@@ -0,0 +1,236 @@
+use rusqlite::{Connection, Result};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+
+use crate::db;
+use crate::download_item::collision::CollisionPolicy;
+
+/// Kinds of file that can be sent somewhere other than the download folder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    Archives,
+    Video,
+    Audio,
+    Images,
+    Documents,
+    Programs,
+}
+
+impl Category {
+    pub const ALL: [Category; 6] = [
+        Category::Archives,
+        Category::Video,
+        Category::Audio,
+        Category::Images,
+        Category::Documents,
+        Category::Programs,
+    ];
+
+    /// Guesses the category from the file extension.
+    pub fn of(file_name: &str) -> Option<Self> {
+        let (_, extension) = file_name.rsplit_once('.')?;
+        let category = match extension.to_ascii_lowercase().as_str() {
+            "zip" | "gz" | "tgz" | "tar" | "bz2" | "xz" | "zst" | "7z" | "rar" | "iso" => {
+                Category::Archives
+            }
+            "mp4" | "mkv" | "webm" | "avi" | "mov" | "m4v" => Category::Video,
+            "mp3" | "ogg" | "flac" | "wav" | "m4a" | "opus" => Category::Audio,
+            "png" | "jpg" | "jpeg" | "gif" | "webp" | "svg" => Category::Images,
+            "pdf" | "epub" | "txt" | "csv" | "doc" | "docx" | "odt" => Category::Documents,
+            "exe" | "msi" | "dmg" | "deb" | "rpm" | "apk" | "appimage" => Category::Programs,
+            _ => return None,
+        };
+        Some(category)
+    }
+
+    /// The settings key its folder is stored under.
+    fn key(&self) -> String {
+        format!("category_dir.{}", self.to_string().to_ascii_lowercase())
+    }
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Category::Archives => write!(f, "Archives"),
+            Category::Video => write!(f, "Video"),
+            Category::Audio => write!(f, "Audio"),
+            Category::Images => write!(f, "Images"),
+            Category::Documents => write!(f, "Documents"),
+            Category::Programs => write!(f, "Programs"),
+        }
+    }
+}
+
+/// Preferences for where downloads are saved and how they are handled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub download_dir: String,
+    /// Folders for categories that shouldn't go to `download_dir`.
+    pub category_dirs: BTreeMap<Category, String>,
+    /// Applies to downloads that don't pick their own collision policy.
+    pub collision_policy: CollisionPolicy,
+    /// Start downloads the server can't resume over from zero instead of
+    /// asking first.
+    pub restart_unresumable: bool,
+    /// Proxy URL for hosts no rule covers; empty uses the environment's.
+    pub proxy: String,
+    /// Host pattern and the proxy (or `direct`) for it, first match wins.
+    pub proxy_rules: Vec<(String, String)>,
+    /// Hosts never sent through a proxy, separated by commas.
+    pub no_proxy: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            download_dir: default_download_dir().to_string_lossy().into_owned(),
+            category_dirs: BTreeMap::new(),
+            collision_policy: CollisionPolicy::default(),
+            restart_unresumable: false,
+            proxy: String::new(),
+            proxy_rules: Vec::new(),
+            no_proxy: String::new(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let mut settings = Self::default();
+        if let Some(dir) = db::load_setting(conn, "download_dir")? {
+            settings.download_dir = dir;
+        }
+        for category in Category::ALL {
+            if let Some(dir) = db::load_setting(conn, &category.key())? {
+                settings.category_dirs.insert(category, dir);
+            }
+        }
+        if let Some(policy) = db::load_setting(conn, "collision_policy")? {
+            settings.collision_policy = CollisionPolicy::parse(&policy).unwrap_or_default();
+        }
+        if let Some(restart) = db::load_setting(conn, "restart_unresumable")? {
+            settings.restart_unresumable = restart == "true";
+        }
+        if let Some(proxy) = db::load_setting(conn, "proxy")? {
+            settings.proxy = proxy;
+        }
+        if let Some(rules) = db::load_setting(conn, "proxy_rules")? {
+            settings.proxy_rules = rules
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .map(|(pattern, proxy)| (pattern.to_string(), proxy.to_string()))
+                .collect();
+        }
+        if let Some(no_proxy) = db::load_setting(conn, "no_proxy")? {
+            settings.no_proxy = no_proxy;
+        }
+        Ok(settings)
+    }
+
+    pub fn save(&self, conn: &Connection) -> Result<()> {
+        db::save_setting(conn, "download_dir", Some(&self.download_dir))?;
+        for category in Category::ALL {
+            let dir = self
+                .category_dirs
+                .get(&category)
+                .filter(|dir| !dir.trim().is_empty());
+            db::save_setting(conn, &category.key(), dir.map(String::as_str))?;
+        }
+        db::save_setting(
+            conn,
+            "collision_policy",
+            Some(self.collision_policy.as_str()),
+        )?;
+        db::save_setting(
+            conn,
+            "restart_unresumable",
+            Some(&self.restart_unresumable.to_string()),
+        )?;
+        db::save_setting(conn, "proxy", Some(&self.proxy))?;
+        // One rule per line, the pattern and then the proxy.
+        let rules: Vec<String> = self
+            .proxy_rules
+            .iter()
+            .filter(|(pattern, _)| !pattern.trim().is_empty())
+            .map(|(pattern, proxy)| format!("{} {}", pattern.trim(), proxy.trim()))
+            .collect();
+        db::save_setting(conn, "proxy_rules", Some(&rules.join("\n")))?;
+        db::save_setting(conn, "no_proxy", Some(&self.no_proxy))
+    }
+
+    /// The folder a file with this name goes to, unless its download
+    /// names one of its own.
+    pub fn dir_for(&self, file_name: &str) -> String {
+        let dir = Category::of(file_name)
+            .and_then(|category| self.category_dirs.get(&category))
+            .filter(|dir| !dir.trim().is_empty())
+            .unwrap_or(&self.download_dir);
+        if dir.trim().is_empty() {
+            return default_download_dir().to_string_lossy().into_owned();
+        }
+        expand_home(dir.trim())
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Expands a leading `~` so folders can be typed the way a shell takes them.
+pub fn expand_home(path: &str) -> String {
+    match (path.strip_prefix('~'), home_dir()) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{}", home.to_string_lossy(), rest)
+        }
+        _ => path.to_string(),
+    }
+}
+
+/// `$XDG_DATA_HOME/hedgehog`, falling back to `~/.local/share/hedgehog`.
+pub fn data_dir() -> PathBuf {
+    std::env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".local/share")))
+        .unwrap_or_default()
+        .join("hedgehog")
+}
+
+pub fn db_path() -> PathBuf {
+    data_dir().join("downloads.db")
+}
+
+/// `$XDG_DOWNLOAD_DIR`, then the one set in `user-dirs.dirs`, then
+/// `~/Downloads`.
+pub fn default_download_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("XDG_DOWNLOAD_DIR").filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(dir);
+    }
+
+    let home = home_dir().unwrap_or_default();
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home.join(".config"));
+
+    user_dirs_download_dir(&config_dir.join("user-dirs.dirs"), &home)
+        .unwrap_or_else(|| home.join("Downloads"))
+}
+
+/// Reads `XDG_DOWNLOAD_DIR="$HOME/..."` from an xdg-user-dirs file.
+fn user_dirs_download_dir(path: &Path, home: &Path) -> Option<PathBuf> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    contents.lines().find_map(|line| {
+        let value = line.trim().strip_prefix("XDG_DOWNLOAD_DIR=")?;
+        let value = value.trim_matches('"');
+        let dir = match value.strip_prefix("$HOME") {
+            Some(rest) => home.join(rest.trim_start_matches('/')),
+            None => PathBuf::from(value),
+        };
+        Some(dir)
+    })
+}
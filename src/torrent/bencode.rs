@@ -0,0 +1,369 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+/// Lists and dictionaries nested deeper than this are taken as an attack
+/// on the stack rather than a real torrent, which nests three or four deep.
+const MAX_DEPTH: usize = 64;
+
+/// A bencoded value, borrowing its strings from the input it was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kind<'a> {
+    /// A byte string, which may or may not be UTF-8.
+    Bytes(&'a [u8]),
+    Integer(i64),
+    List(Vec<Value<'a>>),
+    /// Keys in byte order, as canonical bencode has them.
+    Dict(BTreeMap<&'a [u8], Value<'a>>),
+}
+
+/// A value along with where it was in the input. The exact bytes matter:
+/// an info-hash is taken over the `info` dictionary as it was encoded, and
+/// a different encoding of the same value would hash differently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Value<'a> {
+    pub kind: Kind<'a>,
+    /// Offsets into the input; empty for values built to be encoded.
+    pub span: Range<usize>,
+    raw: &'a [u8],
+}
+
+impl<'a> Value<'a> {
+    pub fn bytes(bytes: &'a [u8]) -> Self {
+        Self::new(Kind::Bytes(bytes))
+    }
+
+    pub fn integer(integer: i64) -> Self {
+        Self::new(Kind::Integer(integer))
+    }
+
+    pub fn list(items: Vec<Value<'a>>) -> Self {
+        Self::new(Kind::List(items))
+    }
+
+    pub fn dict(entries: impl IntoIterator<Item = (&'a [u8], Value<'a>)>) -> Self {
+        Self::new(Kind::Dict(entries.into_iter().collect()))
+    }
+
+    fn new(kind: Kind<'a>) -> Self {
+        Self {
+            kind,
+            span: 0..0,
+            raw: &[],
+        }
+    }
+
+    /// The bytes this value was decoded from, exactly as they were.
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self.kind {
+            Kind::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self.kind {
+            Kind::Integer(integer) => Some(integer),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value<'a>]> {
+        match &self.kind {
+            Kind::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<&'a [u8], Value<'a>>> {
+        match &self.kind {
+            Kind::Dict(entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    /// The entry for `key`, if this is a dictionary that has one.
+    pub fn get(&self, key: &str) -> Option<&Value<'a>> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    /// The canonical encoding: shortest integers and lengths, keys sorted.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        match &self.kind {
+            Kind::Bytes(bytes) => encode_bytes(bytes, out),
+            Kind::Integer(integer) => {
+                out.push(b'i');
+                out.extend_from_slice(integer.to_string().as_bytes());
+                out.push(b'e');
+            }
+            Kind::List(items) => {
+                out.push(b'l');
+                for item in items {
+                    item.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Kind::Dict(entries) => {
+                out.push(b'd');
+                for (key, value) in entries {
+                    encode_bytes(key, out);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+/// Why some input isn't canonical bencode, and where it went wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub offset: usize,
+    pub reason: Reason,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reason {
+    UnexpectedEnd,
+    UnexpectedByte(u8),
+    /// `i03e` or `03:abc`, which have a shorter form.
+    LeadingZero,
+    /// `i-0e`, which is just `i0e`.
+    NegativeZero,
+    Overflow,
+    /// A dictionary key that doesn't come after the one before it.
+    UnsortedKey,
+    DuplicateKey,
+    TooDeep,
+    /// More input after a complete value.
+    TrailingData,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match &self.reason {
+            Reason::UnexpectedEnd => "Unexpected end of input".to_string(),
+            Reason::UnexpectedByte(byte) => format!("Unexpected byte {:?}", char::from(*byte)),
+            Reason::LeadingZero => "Leading zero".to_string(),
+            Reason::NegativeZero => "Negative zero".to_string(),
+            Reason::Overflow => "Number too large".to_string(),
+            Reason::UnsortedKey => "Dictionary keys out of order".to_string(),
+            Reason::DuplicateKey => "Duplicate dictionary key".to_string(),
+            Reason::TooDeep => "Nested too deeply".to_string(),
+            Reason::TrailingData => "Trailing data".to_string(),
+        };
+        write!(f, "{} at byte {}", reason, self.offset)
+    }
+}
+
+/// Decodes exactly one value, which must be all of `input` and in
+/// canonical form, so that encoding it again gives back the same bytes.
+pub fn decode(input: &[u8]) -> Result<Value<'_>, Error> {
+    decode_with(input, true)
+}
+
+/// Like [`decode`], but takes dictionary keys in any order, as some
+/// trackers send them. Only for values that are never hashed.
+pub fn decode_relaxed(input: &[u8]) -> Result<Value<'_>, Error> {
+    decode_with(input, false)
+}
+
+fn decode_with(input: &[u8], strict: bool) -> Result<Value<'_>, Error> {
+    let mut decoder = Decoder {
+        input,
+        position: 0,
+        depth: 0,
+        strict,
+    };
+    let value = decoder.value()?;
+    if decoder.position < input.len() {
+        return Err(decoder.error(Reason::TrailingData));
+    }
+    Ok(value)
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    position: usize,
+    depth: usize,
+    /// Whether dictionary keys have to be sorted.
+    strict: bool,
+}
+
+impl<'a> Decoder<'a> {
+    fn value(&mut self) -> Result<Value<'a>, Error> {
+        let start = self.position;
+        let kind = match self.peek()? {
+            b'i' => {
+                self.position += 1;
+                let integer = self.integer(b'e')?;
+                Kind::Integer(integer)
+            }
+            b'l' => {
+                self.enter()?;
+                let mut items = Vec::new();
+                while self.peek()? != b'e' {
+                    items.push(self.value()?);
+                }
+                self.leave();
+                Kind::List(items)
+            }
+            b'd' => {
+                self.enter()?;
+                let mut entries = BTreeMap::new();
+                let mut previous: Option<&[u8]> = None;
+                while self.peek()? != b'e' {
+                    let key_start = self.position;
+                    let key = self.string()?;
+                    if let Some(previous) = previous {
+                        let reason = match key.cmp(previous) {
+                            std::cmp::Ordering::Equal => Some(Reason::DuplicateKey),
+                            std::cmp::Ordering::Less if self.strict => Some(Reason::UnsortedKey),
+                            _ if entries.contains_key(key) => Some(Reason::DuplicateKey),
+                            _ => None,
+                        };
+                        if let Some(reason) = reason {
+                            return Err(Error {
+                                offset: key_start,
+                                reason,
+                            });
+                        }
+                    }
+                    previous = Some(key);
+                    let value = self.value()?;
+                    entries.insert(key, value);
+                }
+                self.leave();
+                Kind::Dict(entries)
+            }
+            b'0'..=b'9' => Kind::Bytes(self.string()?),
+            byte => return Err(self.error(Reason::UnexpectedByte(byte))),
+        };
+        Ok(Value {
+            kind,
+            span: start..self.position,
+            raw: &self.input[start..self.position],
+        })
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        self.input
+            .get(self.position)
+            .copied()
+            .ok_or_else(|| self.error(Reason::UnexpectedEnd))
+    }
+
+    /// Steps into a list or dictionary.
+    fn enter(&mut self) -> Result<(), Error> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(Reason::TooDeep));
+        }
+        self.depth += 1;
+        self.position += 1;
+        Ok(())
+    }
+
+    /// Steps past the `e` closing a list or dictionary.
+    fn leave(&mut self) {
+        self.depth -= 1;
+        self.position += 1;
+    }
+
+    /// `<length>:<bytes>`
+    fn string(&mut self) -> Result<&'a [u8], Error> {
+        let length_start = self.position;
+        if self.peek()? == b'-' {
+            return Err(self.error(Reason::UnexpectedByte(b'-')));
+        }
+        let length = self.integer(b':')?;
+        let length = usize::try_from(length).map_err(|_| Error {
+            offset: length_start,
+            reason: Reason::Overflow,
+        })?;
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.input.len())
+            .ok_or(Error {
+                offset: self.input.len(),
+                reason: Reason::UnexpectedEnd,
+            })?;
+        let bytes = &self.input[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    /// Digits, optionally negative, up to `end`, which is consumed too.
+    fn integer(&mut self, end: u8) -> Result<i64, Error> {
+        let start = self.position;
+        let negative = self.peek()? == b'-';
+        if negative {
+            self.position += 1;
+        }
+        let digits_start = self.position;
+        let mut integer: i64 = 0;
+        loop {
+            match self.peek()? {
+                byte @ b'0'..=b'9' => {
+                    let digit = i64::from(byte - b'0');
+                    // Built up negative for a negative number, so i64::MIN fits.
+                    integer = integer
+                        .checked_mul(10)
+                        .and_then(|integer| match negative {
+                            true => integer.checked_sub(digit),
+                            false => integer.checked_add(digit),
+                        })
+                        .ok_or(Error {
+                            offset: start,
+                            reason: Reason::Overflow,
+                        })?;
+                    self.position += 1;
+                }
+                byte if byte == end && self.position > digits_start => break,
+                byte => return Err(self.error(Reason::UnexpectedByte(byte))),
+            }
+        }
+        let digits = &self.input[digits_start..self.position];
+        if digits.len() > 1 && digits[0] == b'0' {
+            return Err(Error {
+                offset: digits_start,
+                reason: Reason::LeadingZero,
+            });
+        }
+        if negative && integer == 0 {
+            return Err(Error {
+                offset: start,
+                reason: Reason::NegativeZero,
+            });
+        }
+        self.position += 1;
+        Ok(integer)
+    }
+
+    fn error(&self, reason: Reason) -> Error {
+        Error {
+            offset: self.position,
+            reason,
+        }
+    }
+}
@@ -0,0 +1,813 @@
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::sync::CancellationToken;
+
+use super::metainfo::Metainfo;
+use super::peer::{self, Block, Message, BLOCK_SIZE};
+use super::storage::Storage;
+use super::tracker::{self, Announcer, Counters};
+use super::TorrentItem;
+use crate::settings::Settings;
+
+/// Requests kept outstanding with each unchoked peer, so a block is
+/// always on the way while the last one is being handled.
+const PIPELINE_DEPTH: usize = 16;
+/// Peers connected at once.
+const MAX_PEERS: usize = 30;
+/// How often progress is reported while pieces trickle in.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Peers drop connections that stay quiet for two minutes.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+/// A peer that sends nothing for this long is given up on.
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+/// A peer that dropped is tried again after this, twice as long each time
+/// it drops, up to the maximum.
+const PEER_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_PEER_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+pub struct Job {
+    pub id: i64,
+    pub metainfo: Arc<Metainfo>,
+    pub download_dir: Option<String>,
+    pub settings: Settings,
+    pub uploaded_bytes: u64,
+    pub stop: CancellationToken,
+}
+
+#[derive(Debug, Clone)]
+pub enum Progress {
+    /// Files already on disk are being hashed to see which pieces are done.
+    Checking(f32),
+    /// Percent done, verified bytes, and how many peers are connected.
+    Advanced(f32, u64, usize),
+    Tracker(tracker::Status),
+    /// Piece `n` failed its hash check and the `m` peers that sent parts
+    /// of it were banned.
+    PieceFailed(usize, usize),
+    /// Stopped as asked, with this many bytes verified.
+    Stopped(u64),
+    Finished,
+}
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    Download(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Download(msg) => write!(f, "Download error: {}", msg),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Download(e.to_string())
+    }
+}
+
+pub fn torrent(job: Job) -> iced::Subscription<(i64, Result<Progress, Error>)> {
+    iced::Subscription::run_with_id(
+        (std::any::TypeId::of::<TorrentItem>(), job.id),
+        create_torrent_stream(job),
+    )
+}
+
+/// The engine task is aborted, peers and all, when the stream is dropped.
+struct Engine(JoinHandle<()>);
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+enum State {
+    Ready(Box<Job>),
+    Running(mpsc::UnboundedReceiver<Result<Progress, Error>>, Engine),
+}
+
+fn create_torrent_stream(job: Job) -> impl futures::Stream<Item = (i64, Result<Progress, Error>)> {
+    let id = job.id;
+    futures::stream::unfold(State::Ready(Box::new(job)), move |state| async move {
+        let (mut receiver, engine) = match state {
+            State::Ready(job) => {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                let engine = Engine(tokio::spawn(async move {
+                    if let Err(e) = run(*job, &sender).await {
+                        let _ = sender.send(Err(e));
+                    }
+                }));
+                (receiver, engine)
+            }
+            State::Running(receiver, engine) => (receiver, engine),
+        };
+        let progress = receiver.recv().await?;
+        Some(((id, progress), State::Running(receiver, engine)))
+    })
+}
+
+/// What peer tasks tell the engine.
+enum PeerEvent {
+    Verified,
+    /// A piece failed its hash, and this many peers were banned for it.
+    Failed(usize, usize),
+    Closed(SocketAddr),
+    Error(Error),
+}
+
+/// What every peer task hears about.
+#[derive(Debug, Clone, Copy)]
+enum Broadcast {
+    /// Tell the peer we have this piece now.
+    Have(u32),
+    /// The block arrived from someone else; take back any request for it.
+    Cancel(Block),
+}
+
+/// Everything the peer tasks of a torrent share.
+struct Shared {
+    metainfo: Arc<Metainfo>,
+    storage: Storage,
+    swarm: Mutex<Swarm>,
+    events: mpsc::UnboundedSender<PeerEvent>,
+    broadcast: broadcast::Sender<Broadcast>,
+    stop: CancellationToken,
+}
+
+async fn run(
+    job: Job,
+    progress: &mpsc::UnboundedSender<Result<Progress, Error>>,
+) -> Result<(), Error> {
+    let Job {
+        metainfo,
+        download_dir,
+        settings,
+        uploaded_bytes,
+        stop,
+        ..
+    } = job;
+    let dir = match download_dir {
+        Some(dir) => crate::settings::expand_home(&dir),
+        None => settings.dir_for(&metainfo.name),
+    };
+    let storage = Storage::new(&PathBuf::from(dir), &metainfo);
+    let have = check(&storage, &metainfo, progress, &stop).await?;
+    let swarm = Swarm::new(&storage, have)?;
+    if stop.is_cancelled() {
+        let _ = progress.send(Ok(Progress::Stopped(swarm.downloaded)));
+        return Ok(());
+    }
+    let storage_for_files = storage.clone();
+    tokio::task::spawn_blocking(move || storage_for_files.create_empty_files())
+        .await
+        .map_err(|e| Error::Download(e.to_string()))??;
+
+    let total = metainfo.total_length();
+    if swarm.is_complete() {
+        let _ = progress.send(Ok(Progress::Finished));
+        return Ok(());
+    }
+
+    // Trackers count what was downloaded since `started`.
+    let already_had = swarm.downloaded;
+    let counters = Arc::new(Counters::default());
+    counters.uploaded.store(uploaded_bytes, Ordering::Relaxed);
+    counters
+        .left
+        .store(total - swarm.downloaded, Ordering::Relaxed);
+    let (updates_sender, mut updates) = mpsc::unbounded_channel();
+    // Stops with the torrent, or on its own once everything is in.
+    let tracker_stop = stop.child_token();
+    let announcer = Announcer::new(metainfo.info_hash, metainfo.trackers());
+    let tracker = tokio::spawn(tracker::run(
+        announcer,
+        counters.clone(),
+        tracker_stop.clone(),
+        updates_sender,
+    ));
+
+    let (events_sender, mut events) = mpsc::unbounded_channel();
+    let (broadcast_sender, _) = broadcast::channel(256);
+    let shared = Arc::new(Shared {
+        metainfo: metainfo.clone(),
+        storage,
+        swarm: Mutex::new(swarm),
+        events: events_sender,
+        broadcast: broadcast_sender,
+        stop: stop.child_token(),
+    });
+
+    let mut peers = JoinSet::new();
+    let mut candidates: Vec<SocketAddr> = Vec::new();
+    // Peers that dropped, how often they have, and when to try them again.
+    let mut drops: HashMap<SocketAddr, u32> = HashMap::new();
+    let mut waiting: Vec<(tokio::time::Instant, SocketAddr)> = Vec::new();
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+    let report = |shared: &Shared| {
+        let swarm = shared.swarm.lock().unwrap();
+        let percent = match total {
+            0 => 100.0,
+            total => swarm.downloaded as f32 / total as f32 * 100.0,
+        };
+        (percent, swarm.downloaded, swarm.connected.len())
+    };
+
+    let outcome = loop {
+        tokio::select! {
+            Some(update) = updates.recv() => match update {
+                tracker::Update::Status(status) => {
+                    let _ = progress.send(Ok(Progress::Tracker(status)));
+                }
+                tracker::Update::Peers(addrs) => {
+                    let swarm = shared.swarm.lock().unwrap();
+                    for addr in addrs {
+                        let known = swarm.connected.contains(&addr)
+                            || swarm.banned.contains(&addr.ip())
+                            || candidates.contains(&addr)
+                            || waiting.iter().any(|(_, waiting)| *waiting == addr);
+                        if !known {
+                            candidates.push(addr);
+                        }
+                    }
+                }
+            },
+            Some(event) = events.recv() => match event {
+                PeerEvent::Verified => {
+                    let (percent, downloaded, connected) = report(&shared);
+                    counters
+                        .downloaded
+                        .store(downloaded - already_had, Ordering::Relaxed);
+                    counters.left.store(total - downloaded, Ordering::Relaxed);
+                    let _ = progress.send(Ok(Progress::Advanced(percent, downloaded, connected)));
+                    if shared.swarm.lock().unwrap().is_complete() {
+                        break Ok(true);
+                    }
+                }
+                PeerEvent::Failed(index, banned) => {
+                    let _ = progress.send(Ok(Progress::PieceFailed(index, banned)));
+                }
+                PeerEvent::Closed(addr) => {
+                    shared.swarm.lock().unwrap().connected.remove(&addr);
+                    let dropped = drops.entry(addr).or_default();
+                    let delay = PEER_RETRY_DELAY
+                        .saturating_mul(1 << (*dropped).min(10))
+                        .min(MAX_PEER_RETRY_DELAY);
+                    *dropped += 1;
+                    waiting.push((tokio::time::Instant::now() + delay, addr));
+                }
+                PeerEvent::Error(e) => break Err(e),
+            },
+            _ = ticker.tick() => {
+                let (percent, downloaded, connected) = report(&shared);
+                let _ = progress.send(Ok(Progress::Advanced(percent, downloaded, connected)));
+            }
+            _ = stop.cancelled() => break Ok(false),
+        }
+
+        // Bring the peer count back up from whoever the trackers gave us,
+        // and from dropped peers whose wait is over.
+        let now = tokio::time::Instant::now();
+        waiting.retain(|(until, addr)| {
+            if *until > now {
+                return true;
+            }
+            candidates.push(*addr);
+            false
+        });
+        let mut swarm = shared.swarm.lock().unwrap();
+        while swarm.connected.len() < MAX_PEERS {
+            let Some(addr) = candidates.pop() else {
+                break;
+            };
+            if swarm.banned.contains(&addr.ip()) || swarm.connected.contains(&addr) {
+                continue;
+            }
+            swarm.connected.insert(addr);
+            peers.spawn(run_peer(addr, shared.clone()));
+        }
+    };
+
+    shared.stop.cancel();
+    peers.shutdown().await;
+    match outcome {
+        Ok(true) => {
+            counters.left.store(0, Ordering::Relaxed);
+            tracker_stop.cancel();
+            let _ = tracker.await;
+            let _ = progress.send(Ok(Progress::Finished));
+            Ok(())
+        }
+        Ok(false) => {
+            let _ = tracker.await;
+            let (_, downloaded, _) = report(&shared);
+            let _ = progress.send(Ok(Progress::Stopped(downloaded)));
+            Ok(())
+        }
+        Err(e) => {
+            tracker_stop.cancel();
+            let _ = tracker.await;
+            Err(e)
+        }
+    }
+}
+
+/// Hashes whatever of the torrent is already on disk, so a restart only
+/// fetches the pieces that are missing or wrong.
+async fn check(
+    storage: &Storage,
+    metainfo: &Metainfo,
+    progress: &mpsc::UnboundedSender<Result<Progress, Error>>,
+    stop: &CancellationToken,
+) -> Result<Vec<bool>, Error> {
+    let count = metainfo.pieces.len();
+    let mut have = vec![false; count];
+    let mut last_report = tokio::time::Instant::now();
+    for (index, expected) in metainfo.pieces.iter().enumerate() {
+        if stop.is_cancelled() {
+            break;
+        }
+        let storage = storage.clone();
+        let expected = *expected;
+        have[index] = tokio::task::spawn_blocking(move || {
+            Ok::<_, std::io::Error>(
+                storage
+                    .read_piece(index)?
+                    .is_some_and(|data| Sha1::digest(&data)[..] == expected),
+            )
+        })
+        .await
+        .map_err(|e| Error::Download(e.to_string()))??;
+
+        if last_report.elapsed() >= PROGRESS_INTERVAL {
+            last_report = tokio::time::Instant::now();
+            let percent = index as f32 / count as f32 * 100.0;
+            let _ = progress.send(Ok(Progress::Checking(percent)));
+        }
+    }
+    Ok(have)
+}
+
+/// One connection: handshake, then trade messages until the peer goes
+/// away, misbehaves, gets banned or the torrent stops.
+async fn run_peer(addr: SocketAddr, shared: Arc<Shared>) {
+    if let Err(e) = talk(addr, &shared).await {
+        log::debug!("Peer {} dropped: {}", addr, e);
+    }
+    let _ = shared.events.send(PeerEvent::Closed(addr));
+}
+
+/// Reads messages off its own task, since a read can't be interrupted
+/// halfway; aborted when the connection is done with.
+struct ReadTask(JoinHandle<()>);
+
+impl Drop for ReadTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn talk(addr: SocketAddr, shared: &Shared) -> std::io::Result<()> {
+    let our_id = super::peer_id();
+    let (mut reader, mut writer, their_id) =
+        peer::connect(addr, &shared.metainfo.info_hash, our_id).await?;
+    if &their_id == our_id {
+        return Err(std::io::Error::other("connected to ourselves"));
+    }
+
+    let (incoming_sender, mut incoming) = mpsc::channel(64);
+    let _read_task = ReadTask(tokio::spawn(async move {
+        loop {
+            let message = reader.read().await;
+            let failed = message.is_err();
+            if incoming_sender.send(message).await.is_err() || failed {
+                break;
+            }
+        }
+    }));
+    let mut broadcasts = shared.broadcast.subscribe();
+
+    let piece_count = shared.metainfo.pieces.len();
+    let bitfield = shared.swarm.lock().unwrap().bitfield();
+    if let Some(bitfield) = bitfield {
+        writer.send(&Message::Bitfield(bitfield)).await?;
+    }
+
+    let mut has = vec![false; piece_count];
+    let mut choked = true;
+    let mut interested = false;
+    let mut pending: Vec<Block> = Vec::new();
+    let mut keep_alive = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+    let result = loop {
+        tokio::select! {
+            message = tokio::time::timeout(PEER_IDLE_TIMEOUT, incoming.recv()) => {
+                let message = match message {
+                    Ok(Some(Ok(message))) => message,
+                    Ok(Some(Err(e))) => break Err(e),
+                    Ok(None) => break Ok(()),
+                    Err(_) => break Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "peer went quiet")),
+                };
+                match message {
+                    Message::Bitfield(bits) => {
+                        if bits.len() != piece_count.div_ceil(8) {
+                            break Err(std::io::Error::other("bitfield of the wrong size"));
+                        }
+                        let mut swarm = shared.swarm.lock().unwrap();
+                        for (index, has) in has.iter_mut().enumerate() {
+                            if !*has && peer::has_piece(&bits, index) {
+                                *has = true;
+                                swarm.availability[index] += 1;
+                            }
+                        }
+                    }
+                    Message::Have(index) => {
+                        let index = index as usize;
+                        if index < piece_count && !has[index] {
+                            has[index] = true;
+                            shared.swarm.lock().unwrap().availability[index] += 1;
+                        }
+                    }
+                    Message::Choke => {
+                        choked = true;
+                        shared.swarm.lock().unwrap().release(&pending);
+                        pending.clear();
+                    }
+                    Message::Unchoke => choked = false,
+                    Message::Piece { piece, begin, data } => {
+                        let block = Block { piece, begin, length: data.len() as u32 };
+                        let Some(position) = pending.iter().position(|request| *request == block) else {
+                            continue;
+                        };
+                        pending.swap_remove(position);
+                        let received = shared.swarm.lock().unwrap().receive(block, &data, addr.ip());
+                        match received {
+                            Received::Block { others: true } => {
+                                let _ = shared.broadcast.send(Broadcast::Cancel(block));
+                            }
+                            Received::Block { .. } => {}
+                            Received::Piece(index, data) => {
+                                if let Err(e) = finish_piece(shared, index, data).await {
+                                    break Err(e);
+                                }
+                            }
+                        }
+                    }
+                    // Peers stay choked: this client only downloads.
+                    Message::Request(_)
+                    | Message::Cancel(_)
+                    | Message::Interested
+                    | Message::NotInterested
+                    | Message::KeepAlive
+                    | Message::Other(_) => {}
+                }
+            }
+            broadcast = broadcasts.recv() => {
+                let sent = match broadcast {
+                    Ok(Broadcast::Have(index)) => writer.send(&Message::Have(index)).await,
+                    Ok(Broadcast::Cancel(block)) => {
+                        match pending.iter().position(|request| *request == block) {
+                            Some(position) => {
+                                pending.swap_remove(position);
+                                writer.send(&Message::Cancel(block)).await
+                            }
+                            None => Ok(()),
+                        }
+                    }
+                    Err(_) => Ok(()),
+                };
+                if let Err(e) = sent {
+                    break Err(e);
+                }
+            }
+            _ = keep_alive.tick() => {
+                if let Err(e) = writer.send(&Message::KeepAlive).await {
+                    break Err(e);
+                }
+            }
+            _ = shared.stop.cancelled() => break Ok(()),
+        }
+
+        let requests = {
+            let mut swarm = shared.swarm.lock().unwrap();
+            if swarm.banned.contains(&addr.ip()) {
+                break Err(std::io::Error::other("sent a bad piece"));
+            }
+            if swarm.is_complete() {
+                break Ok(());
+            }
+            let wants = swarm.wants(&has);
+            if wants != interested {
+                interested = wants;
+                None
+            } else if choked || !interested {
+                Some(Vec::new())
+            } else {
+                let mut requests = Vec::new();
+                while pending.len() < PIPELINE_DEPTH {
+                    match swarm.pick(&has, &pending) {
+                        Some(block) => {
+                            pending.push(block);
+                            requests.push(block);
+                        }
+                        None => break,
+                    }
+                }
+                Some(requests)
+            }
+        };
+        let sent = match requests {
+            None if interested => writer.send(&Message::Interested).await,
+            None => writer.send(&Message::NotInterested).await,
+            Some(requests) => {
+                let mut sent = Ok(());
+                for block in requests {
+                    sent = writer.send(&Message::Request(block)).await;
+                    if sent.is_err() {
+                        break;
+                    }
+                }
+                sent
+            }
+        };
+        if let Err(e) = sent {
+            break Err(e);
+        }
+    };
+
+    // Whatever ended the connection, its requests go back to the pool and
+    // its pieces stop counting towards availability.
+    let mut swarm = shared.swarm.lock().unwrap();
+    swarm.release(&pending);
+    for (index, has) in has.iter().enumerate() {
+        if *has {
+            swarm.availability[index] -= 1;
+        }
+    }
+    result
+}
+
+/// Checks a complete piece against its hash and writes it out, or throws
+/// it away and bans everyone who sent part of it.
+async fn finish_piece(shared: &Shared, index: usize, data: Vec<u8>) -> std::io::Result<()> {
+    let expected = shared.metainfo.pieces[index];
+    let storage = shared.storage.clone();
+    let written = tokio::task::spawn_blocking(move || {
+        if Sha1::digest(&data)[..] != expected {
+            return Ok(false);
+        }
+        storage.write_piece(index, &data).map(|_| true)
+    })
+    .await
+    .map_err(std::io::Error::other)?;
+
+    match written {
+        Ok(true) => {
+            shared.swarm.lock().unwrap().verified(index);
+            let _ = shared.broadcast.send(Broadcast::Have(index as u32));
+            let _ = shared.events.send(PeerEvent::Verified);
+            Ok(())
+        }
+        Ok(false) => {
+            let banned = shared.swarm.lock().unwrap().failed(index);
+            log::warn!("Piece {} failed its hash; banned {} peers", index, banned);
+            let _ = shared.events.send(PeerEvent::Failed(index, banned));
+            Ok(())
+        }
+        // The disk is the problem, not the peer, so the whole torrent stops.
+        Err(e) => {
+            let _ = shared.events.send(PeerEvent::Error(e.into()));
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Slot {
+    /// Not here yet, with this many peers asked for it.
+    Wanted(u8),
+    Received,
+}
+
+/// A piece being put together from blocks.
+struct ActivePiece {
+    data: Vec<u8>,
+    slots: Vec<Slot>,
+    /// Who sent its blocks, to ban if the piece turns out bad.
+    senders: HashSet<IpAddr>,
+}
+
+enum Received {
+    /// `others` when another peer was asked for the same block too.
+    Block { others: bool },
+    /// The last block is in; the piece is handed over to be checked.
+    Piece(usize, Vec<u8>),
+}
+
+/// Which pieces are done, which are being fetched and who is connected.
+struct Swarm {
+    have: Vec<bool>,
+    /// How many connected peers have each piece, for rarest first.
+    availability: Vec<u32>,
+    active: HashMap<usize, ActivePiece>,
+    /// Complete pieces out being hashed and written.
+    finishing: HashSet<usize>,
+    piece_sizes: Vec<u32>,
+    downloaded: u64,
+    connected: HashSet<SocketAddr>,
+    banned: HashSet<IpAddr>,
+}
+
+impl Swarm {
+    fn new(storage: &Storage, have: Vec<bool>) -> Result<Self, Error> {
+        let piece_sizes = (0..have.len())
+            .map(|index| u32::try_from(storage.piece_size(index)))
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| Error::Download("piece too large".to_string()))?;
+        let downloaded = have
+            .iter()
+            .zip(&piece_sizes)
+            .filter(|(have, _)| **have)
+            .map(|(_, size)| u64::from(*size))
+            .sum();
+        Ok(Self {
+            availability: vec![0; have.len()],
+            have,
+            active: HashMap::new(),
+            finishing: HashSet::new(),
+            piece_sizes,
+            downloaded,
+            connected: HashSet::new(),
+            banned: HashSet::new(),
+        })
+    }
+
+    fn is_complete(&self) -> bool {
+        self.have.iter().all(|have| *have)
+    }
+
+    /// Our pieces for a new peer, if there are any to tell of.
+    fn bitfield(&self) -> Option<Vec<u8>> {
+        if !self.have.iter().any(|have| *have) {
+            return None;
+        }
+        let mut bits = vec![0u8; self.have.len().div_ceil(8)];
+        for (index, _) in self.have.iter().enumerate().filter(|(_, have)| **have) {
+            bits[index / 8] |= 0x80 >> (index % 8);
+        }
+        Some(bits)
+    }
+
+    /// Whether a peer with `has` has anything still needed.
+    fn wants(&self, has: &[bool]) -> bool {
+        has.iter()
+            .zip(&self.have)
+            .enumerate()
+            .any(|(index, (has, have))| *has && !*have && !self.finishing.contains(&index))
+    }
+
+    /// The next block to ask a peer for: the rest of a piece already
+    /// started, else the start of the rarest piece the peer has. Once
+    /// every block has been asked for, blocks still out with other peers
+    /// are asked for again so a slow peer can't hold up the end.
+    fn pick(&mut self, has: &[bool], pending: &[Block]) -> Option<Block> {
+        let started = self
+            .active
+            .iter()
+            .filter(|(index, _)| has[**index])
+            .find_map(|(index, piece)| {
+                let slot = piece
+                    .slots
+                    .iter()
+                    .position(|slot| *slot == Slot::Wanted(0))?;
+                Some((*index, slot))
+            });
+        let (index, slot) = match started {
+            Some(started) => started,
+            None => match self.rarest(has) {
+                Some(index) => {
+                    let size = self.piece_sizes[index];
+                    self.active.insert(
+                        index,
+                        ActivePiece {
+                            data: vec![0; size as usize],
+                            slots: vec![Slot::Wanted(0); size.div_ceil(BLOCK_SIZE) as usize],
+                            senders: HashSet::new(),
+                        },
+                    );
+                    (index, 0)
+                }
+                None => self.endgame(has, pending)?,
+            },
+        };
+
+        let piece = self.active.get_mut(&index)?;
+        if let Slot::Wanted(asked) = &mut piece.slots[slot] {
+            *asked += 1;
+        }
+        let begin = slot as u32 * BLOCK_SIZE;
+        Some(Block {
+            piece: index as u32,
+            begin,
+            length: BLOCK_SIZE.min(self.piece_sizes[index] - begin),
+        })
+    }
+
+    fn rarest(&self, has: &[bool]) -> Option<usize> {
+        (0..self.have.len())
+            .filter(|index| has[*index] && !self.have[*index])
+            .filter(|index| !self.active.contains_key(index) && !self.finishing.contains(index))
+            .min_by_key(|index| self.availability[*index])
+    }
+
+    fn endgame(&self, has: &[bool], pending: &[Block]) -> Option<(usize, usize)> {
+        self.active
+            .iter()
+            .filter(|(index, _)| has[**index])
+            .find_map(|(index, piece)| {
+                let slot = piece.slots.iter().enumerate().position(|(slot, state)| {
+                    matches!(state, Slot::Wanted(_))
+                        && !pending.iter().any(|block| {
+                            block.piece as usize == *index
+                                && block.begin == slot as u32 * BLOCK_SIZE
+                        })
+                })?;
+                Some((*index, slot))
+            })
+    }
+
+    /// Gives back requests a peer won't be answering.
+    fn release(&mut self, blocks: &[Block]) {
+        for block in blocks {
+            let slot = (block.begin / BLOCK_SIZE) as usize;
+            if let Some(Slot::Wanted(asked)) = self
+                .active
+                .get_mut(&(block.piece as usize))
+                .and_then(|piece| piece.slots.get_mut(slot))
+            {
+                *asked = asked.saturating_sub(1);
+            }
+        }
+    }
+
+    fn receive(&mut self, block: Block, data: &[u8], from: IpAddr) -> Received {
+        let index = block.piece as usize;
+        let Some(piece) = self.active.get_mut(&index) else {
+            return Received::Block { others: false };
+        };
+        let slot = (block.begin / BLOCK_SIZE) as usize;
+        let others = match piece.slots.get(slot) {
+            Some(Slot::Wanted(asked)) => *asked > 1,
+            _ => return Received::Block { others: false },
+        };
+        let begin = block.begin as usize;
+        piece.data[begin..begin + data.len()].copy_from_slice(data);
+        piece.slots[slot] = Slot::Received;
+        piece.senders.insert(from);
+
+        if piece.slots.iter().all(|slot| *slot == Slot::Received) {
+            let piece = self.active.remove(&index).unwrap();
+            self.finishing.insert(index);
+            // Kept for `failed`, which needs to know who to ban.
+            self.active.insert(
+                index,
+                ActivePiece {
+                    data: Vec::new(),
+                    slots: Vec::new(),
+                    senders: piece.senders,
+                },
+            );
+            return Received::Piece(index, piece.data);
+        }
+        Received::Block { others }
+    }
+
+    fn verified(&mut self, index: usize) {
+        self.active.remove(&index);
+        self.finishing.remove(&index);
+        self.have[index] = true;
+        self.downloaded += u64::from(self.piece_sizes[index]);
+    }
+
+    /// Starts a bad piece over and bans its senders, returning how many.
+    fn failed(&mut self, index: usize) -> usize {
+        self.finishing.remove(&index);
+        let senders = self
+            .active
+            .remove(&index)
+            .map(|piece| piece.senders)
+            .unwrap_or_default();
+        let banned = senders.len();
+        self.banned.extend(senders);
+        banned
+    }
+}
@@ -0,0 +1,315 @@
+use reqwest::Url;
+use sha1::{Digest, Sha1};
+use std::fmt;
+
+use super::bencode::{self, Value};
+use crate::download_item::headers::RequestHeaders;
+use crate::download_item::naming;
+use crate::utils::http;
+
+/// What servers send `.torrent` files as.
+pub const CONTENT_TYPE: &str = "application/x-bittorrent";
+/// Even a torrent of a huge file with small pieces stays well under this.
+const MAX_TORRENT_SIZE: usize = 64 * 1024 * 1024;
+/// Each piece is named by its SHA-1 in `pieces`.
+const PIECE_HASH_LEN: usize = 20;
+/// Piece lengths clients actually use; a piece is held in memory whole
+/// while its blocks come in, so anything bigger is refused.
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 64 * 1024 * 1024;
+
+/// The SHA-1 of the bencoded `info` dictionary, which is how trackers and
+/// peers know a torrent.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InfoHash(pub [u8; 20]);
+
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl fmt::Debug for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InfoHash({})", self)
+    }
+}
+
+/// One file of a torrent, in the order its bytes come in the pieces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentFile {
+    /// Below the torrent's folder for a multi-file torrent, or just the
+    /// torrent's name for a single file. Each part is safe to create.
+    pub path: Vec<String>,
+    pub length: u64,
+}
+
+/// What a `.torrent` file says: the files, how they're cut into pieces,
+/// and where to find peers.
+#[derive(Clone, PartialEq)]
+pub struct Metainfo {
+    pub info_hash: InfoHash,
+    pub name: String,
+    pub piece_length: u64,
+    pub pieces: Vec<[u8; PIECE_HASH_LEN]>,
+    pub files: Vec<TorrentFile>,
+    /// Whether the files go in a folder named `name`.
+    pub multi_file: bool,
+    pub announce: Option<String>,
+    /// Tiers of trackers (BEP 12), tried in order; empty if not given.
+    pub announce_list: Vec<Vec<String>>,
+    /// Peers only come from the trackers, not DHT or peer exchange (BEP 27).
+    pub private: bool,
+    /// The `.torrent` file itself, which is what gets saved.
+    pub bytes: Vec<u8>,
+}
+
+// The piece hashes and raw file would drown everything else.
+impl fmt::Debug for Metainfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metainfo")
+            .field("info_hash", &self.info_hash)
+            .field("name", &self.name)
+            .field("piece_length", &self.piece_length)
+            .field("pieces", &self.pieces.len())
+            .field("files", &self.files)
+            .field("announce", &self.announce)
+            .field("announce_list", &self.announce_list)
+            .field("private", &self.private)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Metainfo {
+    /// Reads a v1 (or hybrid) `.torrent` file.
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let root = bencode::decode(bytes).map_err(|e| format!("Invalid torrent: {}", e))?;
+        let info = root.get("info").ok_or("Torrent has no info dictionary")?;
+        if info.as_dict().is_none() {
+            return Err("Torrent info isn't a dictionary".to_string());
+        }
+        let info_hash = InfoHash(Sha1::digest(info.raw()).into());
+
+        let name = utf8_field(info, "name")
+            .map(naming::sanitize)
+            .filter(|name| !name.is_empty())
+            .ok_or("Torrent has no name")?;
+        let piece_length = info
+            .get("piece length")
+            .and_then(Value::as_integer)
+            .and_then(|length| u64::try_from(length).ok())
+            .filter(|length| *length > 0)
+            .ok_or("Torrent has no piece length")?;
+        if !piece_length.is_power_of_two()
+            || !(MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&piece_length)
+        {
+            return Err(format!(
+                "Torrent piece length {} isn't supported",
+                piece_length
+            ));
+        }
+        let pieces = match info.get("pieces").and_then(Value::as_bytes) {
+            Some(pieces) if pieces.len() % PIECE_HASH_LEN == 0 => pieces
+                .chunks_exact(PIECE_HASH_LEN)
+                .map(|hash| hash.try_into().unwrap())
+                .collect::<Vec<_>>(),
+            Some(_) => return Err("Torrent piece hashes are cut short".to_string()),
+            None if info.get("meta version").is_some() => {
+                return Err("v2-only torrents aren't supported".to_string())
+            }
+            None => return Err("Torrent has no piece hashes".to_string()),
+        };
+
+        let (files, multi_file) = match (info.get("length"), info.get("files")) {
+            (Some(length), _) => {
+                let length = length_of(length).ok_or("Torrent has an invalid length")?;
+                let file = TorrentFile {
+                    path: vec![name.clone()],
+                    length,
+                };
+                (vec![file], false)
+            }
+            (None, Some(files)) => {
+                let files = files
+                    .as_list()
+                    .ok_or("Torrent files aren't a list")?
+                    .iter()
+                    .map(parse_file)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if files.is_empty() {
+                    return Err("Torrent has no files".to_string());
+                }
+                (files, true)
+            }
+            (None, None) => return Err("Torrent has neither length nor files".to_string()),
+        };
+
+        let total_length = files
+            .iter()
+            .try_fold(0u64, |total, file| total.checked_add(file.length))
+            .ok_or("Torrent is too large")?;
+        if total_length.div_ceil(piece_length) != pieces.len() as u64 {
+            return Err(format!(
+                "Torrent has {} piece hashes for {} bytes in pieces of {}",
+                pieces.len(),
+                total_length,
+                piece_length
+            ));
+        }
+
+        let announce = root
+            .get("announce")
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_string);
+        let announce_list = root
+            .get("announce-list")
+            .and_then(Value::as_list)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(Value::as_list)
+            .map(|tier| {
+                tier.iter()
+                    .filter_map(Value::as_str)
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+
+        Ok(Self {
+            info_hash,
+            name,
+            piece_length,
+            pieces,
+            files,
+            multi_file,
+            announce,
+            announce_list,
+            private: info.get("private").and_then(Value::as_integer) == Some(1),
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|file| file.length).sum()
+    }
+
+    /// Trackers tier by tier: `announce-list` when there is one, since
+    /// clients that know it ignore `announce` (BEP 12).
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        if !self.announce_list.is_empty() {
+            return self.announce_list.clone();
+        }
+        self.announce.iter().map(|url| vec![url.clone()]).collect()
+    }
+}
+
+/// A field as UTF-8, preferring the `.utf-8` variant some clients add
+/// alongside a name in another encoding.
+fn utf8_field<'a>(dict: &Value<'a>, key: &str) -> Option<&'a str> {
+    dict.get(&format!("{}.utf-8", key))
+        .and_then(Value::as_str)
+        .or_else(|| dict.get(key).and_then(Value::as_str))
+}
+
+fn length_of(value: &Value) -> Option<u64> {
+    value
+        .as_integer()
+        .and_then(|length| u64::try_from(length).ok())
+}
+
+/// One entry of a multi-file torrent's `files`. Path parts that would
+/// climb out of the torrent's folder are refused rather than cleaned up.
+fn parse_file(file: &Value) -> Result<TorrentFile, String> {
+    let length = file
+        .get("length")
+        .and_then(length_of)
+        .ok_or("Torrent file has no length")?;
+    let parts = file
+        .get("path.utf-8")
+        .or_else(|| file.get("path"))
+        .and_then(Value::as_list)
+        .filter(|parts| !parts.is_empty())
+        .ok_or("Torrent file has no path")?;
+    let path = parts
+        .iter()
+        .map(|part| {
+            let part = part.as_str().ok_or("Torrent file path isn't UTF-8")?;
+            if matches!(part, "." | "..") {
+                return Err(format!(
+                    "Torrent file path goes outside its folder: {}",
+                    part
+                ));
+            }
+            let part = naming::sanitize(part);
+            if part.is_empty() {
+                return Err("Torrent file path has an empty part".to_string());
+            }
+            Ok(part)
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(TorrentFile { path, length })
+}
+
+/// Whether `location` (a URL or a path) names a torrent, by its content
+/// type when one is known or else by its extension.
+pub fn is_torrent(location: &str, content_type: Option<&str>) -> bool {
+    if let Some(content_type) = content_type {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        if mime.eq_ignore_ascii_case(CONTENT_TYPE) {
+            return true;
+        }
+    }
+
+    let path = match Url::parse(location) {
+        Ok(url) => url.path().to_string(),
+        Err(_) => location.to_string(),
+    };
+    path.to_ascii_lowercase().ends_with(".torrent")
+}
+
+/// Reads and parses a torrent from an http(s) URL, a `file://` URL or a
+/// local path.
+pub async fn load(location: &str, headers: &RequestHeaders) -> Result<Metainfo, String> {
+    let bytes = match Url::parse(location) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => fetch(url, headers).await?,
+        Ok(url) if url.scheme() == "file" => {
+            let path = url
+                .to_file_path()
+                .map_err(|_| format!("Not a local path: {}", location))?;
+            read(&path.to_string_lossy()).await?
+        }
+        _ => read(location).await?,
+    };
+    Metainfo::parse(&bytes)
+}
+
+async fn fetch(url: Url, headers: &RequestHeaders) -> Result<Vec<u8>, String> {
+    let response = headers
+        .send(http::client().get(url.clone()), url.as_str(), url.as_str())
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| e.to_string())?;
+    if response
+        .content_length()
+        .is_some_and(|len| len > MAX_TORRENT_SIZE as u64)
+    {
+        return Err("Torrent is too big".to_string());
+    }
+    let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+    if bytes.len() > MAX_TORRENT_SIZE {
+        return Err("Torrent is too big".to_string());
+    }
+    Ok(bytes.to_vec())
+}
+
+async fn read(path: &str) -> Result<Vec<u8>, String> {
+    let path = crate::settings::expand_home(path);
+    tokio::fs::read(&path)
+        .await
+        .map_err(|e| format!("Couldn't read {}: {}", path, e))
+}
@@ -0,0 +1,302 @@
+use iced::{
+    widget::{button, column, row, text},
+    Element, Subscription, Task,
+};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::fmt::Display;
+use std::sync::{Arc, OnceLock};
+use tokio_util::sync::CancellationToken;
+
+use metainfo::Metainfo;
+
+use crate::download_item::format_bytes;
+use crate::settings::Settings;
+
+// Nothing in the app builds bencode to encode yet.
+#[allow(dead_code)]
+pub mod bencode;
+pub mod download;
+pub mod metainfo;
+pub mod peer;
+pub mod storage;
+pub mod tracker;
+
+/// Azureus-style client and version at the front of the peer id.
+const PEER_ID_PREFIX: &[u8; 8] = b"-HH0010-";
+/// The port announced to trackers for peers to reach this client on.
+pub const PORT: u16 = 6881;
+
+/// The peer id this client uses for every torrent until it quits.
+pub fn peer_id() -> &'static [u8; 20] {
+    static PEER_ID: OnceLock<[u8; 20]> = OnceLock::new();
+    PEER_ID.get_or_init(|| {
+        let mut id = [0u8; 20];
+        id[..PEER_ID_PREFIX.len()].copy_from_slice(PEER_ID_PREFIX);
+        let mut rng = rand::thread_rng();
+        id[PEER_ID_PREFIX.len()..]
+            .iter_mut()
+            .for_each(|byte| *byte = rng.sample(Alphanumeric));
+        id
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum TorrentStatus {
+    /// Added, or stopped by the user, and not waiting to run.
+    #[default]
+    Stopped,
+    /// Waiting for one of the active slots to free up.
+    Queued,
+    /// Hashing what's already on disk before asking peers for the rest.
+    Checking {
+        progress: f32,
+    },
+    Downloading {
+        progress: f32,
+    },
+    Completed,
+    Failed(String),
+}
+
+impl Display for TorrentStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TorrentStatus::Stopped => write!(f, "Stopped"),
+            TorrentStatus::Queued => write!(f, "Queued"),
+            TorrentStatus::Checking { progress } => write!(f, "Checking: {:.1}%", progress),
+            TorrentStatus::Downloading { progress } => {
+                write!(f, "Downloading: {:.1}%", progress)
+            }
+            TorrentStatus::Completed => write!(f, "Completed"),
+            TorrentStatus::Failed(msg) => write!(f, "Failed: {}", msg),
+        }
+    }
+}
+
+/// A torrent on the list, next to the plain downloads.
+#[derive(Debug, Clone)]
+pub struct TorrentItem {
+    pub id: i64,
+    pub metainfo: Arc<Metainfo>,
+    pub status: TorrentStatus,
+    /// Folder to save into instead of the one the settings pick.
+    pub download_dir: Option<String>,
+    /// Position in the queue; lower starts first.
+    pub priority: i64,
+    /// Bytes of pieces that arrived and matched their hash.
+    pub downloaded_bytes: u64,
+    /// Bytes sent to other peers, which trackers want to hear about.
+    pub uploaded_bytes: u64,
+    pub tracker: tracker::Status,
+    /// Peers connected while running.
+    pub peers: usize,
+    /// The last piece that failed its hash, shown until the torrent restarts.
+    pub notice: Option<String>,
+    /// Tells the running torrent to wind down; a fresh one is made per start.
+    pub stop: CancellationToken,
+}
+
+#[derive(Debug, Clone)]
+pub enum TorrentMessage {
+    Queue,
+    Start,
+    /// Stop pressed in the UI, or the schedule closing.
+    Stop,
+    /// The trackers were told and the torrent is no longer running, with
+    /// the bytes verified by then.
+    Stopped(u64),
+    Checking(f32),
+    /// Percent done, verified bytes and connected peers.
+    Progress(f32, u64, usize),
+    /// A piece failed its hash, and this many peers were banned for it.
+    PieceFailed(usize, usize),
+    Tracker(tracker::Status),
+    Completed,
+    Failed(String),
+    /// Takes the torrent off the list; the app drops it. Downloaded files stay.
+    Remove,
+}
+
+impl TorrentItem {
+    pub fn new(metainfo: Metainfo) -> Self {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+
+        Self {
+            id,
+            metainfo: Arc::new(metainfo),
+            status: TorrentStatus::default(),
+            download_dir: None,
+            priority: 0,
+            downloaded_bytes: 0,
+            uploaded_bytes: 0,
+            tracker: tracker::Status::default(),
+            peers: 0,
+            notice: None,
+            stop: CancellationToken::new(),
+        }
+    }
+
+    pub fn update(&mut self, message: TorrentMessage) -> Task<TorrentMessage> {
+        match message {
+            TorrentMessage::Queue => {
+                self.status = TorrentStatus::Queued;
+                Task::none()
+            }
+            TorrentMessage::Start => {
+                self.stop = CancellationToken::new();
+                self.tracker = tracker::Status::Announcing;
+                self.notice = None;
+                self.status = TorrentStatus::Checking { progress: 0.0 };
+                Task::none()
+            }
+            // A running torrent reports back with `Stopped` once the trackers know.
+            TorrentMessage::Stop if self.is_active() => {
+                self.stop.cancel();
+                Task::none()
+            }
+            TorrentMessage::Stop => {
+                self.status = TorrentStatus::Stopped;
+                self.tracker = tracker::Status::Idle;
+                Task::none()
+            }
+            TorrentMessage::Stopped(bytes) => {
+                self.downloaded_bytes = bytes;
+                self.status = TorrentStatus::Stopped;
+                self.tracker = tracker::Status::Idle;
+                self.peers = 0;
+                Task::none()
+            }
+            TorrentMessage::Checking(progress) => {
+                self.status = TorrentStatus::Checking { progress };
+                Task::none()
+            }
+            TorrentMessage::Progress(progress, bytes, peers) => {
+                self.downloaded_bytes = bytes;
+                self.peers = peers;
+                self.status = TorrentStatus::Downloading { progress };
+                Task::none()
+            }
+            TorrentMessage::PieceFailed(piece, banned) => {
+                self.notice = Some(format!(
+                    "Piece {} failed its hash check; banned {} peers",
+                    piece, banned
+                ));
+                Task::none()
+            }
+            TorrentMessage::Tracker(status) => {
+                self.tracker = status;
+                Task::none()
+            }
+            TorrentMessage::Completed => {
+                self.downloaded_bytes = self.metainfo.total_length();
+                self.status = TorrentStatus::Completed;
+                self.tracker = tracker::Status::Idle;
+                self.peers = 0;
+                Task::none()
+            }
+            TorrentMessage::Failed(e) => {
+                self.status = TorrentStatus::Failed(e);
+                self.tracker = tracker::Status::Idle;
+                self.peers = 0;
+                Task::none()
+            }
+            TorrentMessage::Remove => {
+                self.stop.cancel();
+                self.status = TorrentStatus::Stopped;
+                Task::none()
+            }
+        }
+    }
+
+    pub fn view(&self) -> Element<'_, TorrentMessage> {
+        let metainfo = &self.metainfo;
+        let files = match metainfo.files.len() {
+            1 => "1 file".to_string(),
+            n => format!("{} files", n),
+        };
+        let mut title = format!(
+            "{} ({}, {}, {} pieces of {})",
+            metainfo.name,
+            format_bytes(metainfo.total_length()),
+            files,
+            metainfo.pieces.len(),
+            format_bytes(metainfo.piece_length)
+        );
+        if metainfo.private {
+            title.push_str(" [private]");
+        }
+
+        let controls = match self.status {
+            TorrentStatus::Queued
+            | TorrentStatus::Checking { .. }
+            | TorrentStatus::Downloading { .. } => {
+                row![button("stop")
+                    .on_press_maybe((!self.stop.is_cancelled()).then_some(TorrentMessage::Stop))]
+            }
+            TorrentStatus::Completed => row![],
+            _ => row![button("start").on_press(TorrentMessage::Queue)],
+        }
+        .push(button("remove").on_press(TorrentMessage::Remove))
+        .spacing(10);
+
+        let trackers = metainfo.trackers().iter().map(Vec::len).sum::<usize>();
+        let details = format!("Info-hash {}, {} trackers", metainfo.info_hash, trackers);
+        let status = match self.status {
+            TorrentStatus::Checking { progress } | TorrentStatus::Downloading { progress }
+                if self.stop.is_cancelled() =>
+            {
+                format!("Stopping: {:.1}%", progress)
+            }
+            TorrentStatus::Downloading { progress } => format!(
+                "Downloading: {:.1}% ({} of {}, {} peers)",
+                progress,
+                format_bytes(self.downloaded_bytes),
+                format_bytes(metainfo.total_length()),
+                self.peers
+            ),
+            _ => self.status.to_string(),
+        };
+
+        let mut content = column![text(title), controls, text(status)];
+        if let Some(notice) = &self.notice {
+            content = content.push(text(notice.as_str()).size(12));
+        }
+        content
+            .push(text(self.tracker.to_string()).size(12))
+            .push(text(details).size(12))
+            .into()
+    }
+
+    /// Fetches pieces from peers, and announces, for as long as the
+    /// torrent runs.
+    pub fn subscription(
+        &self,
+        settings: &Settings,
+    ) -> Subscription<(i64, Result<download::Progress, download::Error>)> {
+        if !self.is_active() {
+            return Subscription::none();
+        }
+        download::torrent(download::Job {
+            id: self.id,
+            metainfo: self.metainfo.clone(),
+            download_dir: self.download_dir.clone(),
+            settings: settings.clone(),
+            uploaded_bytes: self.uploaded_bytes,
+            stop: self.stop.clone(),
+        })
+    }
+
+    /// Whether this torrent is holding one of the active slots.
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self.status,
+            TorrentStatus::Checking { .. } | TorrentStatus::Downloading { .. }
+        )
+    }
+}
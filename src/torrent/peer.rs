@@ -0,0 +1,179 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use super::metainfo::InfoHash;
+
+/// The size of block everyone requests; peers may refuse anything larger.
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+/// Protocol name length and name, eight reserved bytes, info-hash, peer id.
+const HANDSHAKE_LEN: usize = 1 + 19 + 8 + 20 + 20;
+/// A block with its header fits easily; so does the bitfield of any
+/// torrent that would also fit in memory.
+const MAX_MESSAGE_LEN: u32 = 1024 * 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Part of a piece, as a request, cancel or piece message names it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    /// Which pieces the peer has, high bit of the first byte first.
+    Bitfield(Vec<u8>),
+    Request(Block),
+    Piece {
+        piece: u32,
+        begin: u32,
+        data: Vec<u8>,
+    },
+    Cancel(Block),
+    /// Extensions this client doesn't speak, such as DHT's `port`.
+    Other(u8),
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let block = |id: u8, block: &Block| {
+            let mut payload = vec![id];
+            payload.extend_from_slice(&block.piece.to_be_bytes());
+            payload.extend_from_slice(&block.begin.to_be_bytes());
+            payload.extend_from_slice(&block.length.to_be_bytes());
+            payload
+        };
+        let payload = match self {
+            Message::KeepAlive => Vec::new(),
+            Message::Choke => vec![0],
+            Message::Unchoke => vec![1],
+            Message::Interested => vec![2],
+            Message::NotInterested => vec![3],
+            Message::Have(piece) => [&[4][..], &piece.to_be_bytes()].concat(),
+            Message::Bitfield(bits) => [&[5][..], bits].concat(),
+            Message::Request(request) => block(6, request),
+            Message::Piece { piece, begin, data } => {
+                [&[7][..], &piece.to_be_bytes(), &begin.to_be_bytes(), data].concat()
+            }
+            Message::Cancel(request) => block(8, request),
+            Message::Other(id) => vec![*id],
+        };
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    fn decode(payload: Vec<u8>) -> io::Result<Self> {
+        let Some((&id, body)) = payload.split_first() else {
+            return Ok(Message::KeepAlive);
+        };
+        let int = |offset: usize| -> io::Result<u32> {
+            body.get(offset..offset + 4)
+                .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+                .ok_or_else(|| invalid(format!("message {} cut short", id)))
+        };
+        let block = || -> io::Result<Block> {
+            Ok(Block {
+                piece: int(0)?,
+                begin: int(4)?,
+                length: int(8)?,
+            })
+        };
+        Ok(match id {
+            0 => Message::Choke,
+            1 => Message::Unchoke,
+            2 => Message::Interested,
+            3 => Message::NotInterested,
+            4 => Message::Have(int(0)?),
+            5 => Message::Bitfield(body.to_vec()),
+            6 => Message::Request(block()?),
+            7 => Message::Piece {
+                piece: int(0)?,
+                begin: int(4)?,
+                data: body[8..].to_vec(),
+            },
+            8 => Message::Cancel(block()?),
+            id => Message::Other(id),
+        })
+    }
+}
+
+/// Connects to a peer and swaps handshakes, returning the peer's id.
+/// A peer that answers for a different torrent is dropped.
+pub async fn connect(
+    addr: SocketAddr,
+    info_hash: &InfoHash,
+    peer_id: &[u8; 20],
+) -> io::Result<(Reader, Writer, [u8; 20])> {
+    let mut stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??;
+
+    let mut handshake = Vec::with_capacity(HANDSHAKE_LEN);
+    handshake.push(PROTOCOL.len() as u8);
+    handshake.extend_from_slice(PROTOCOL);
+    handshake.extend_from_slice(&[0; 8]);
+    handshake.extend_from_slice(&info_hash.0);
+    handshake.extend_from_slice(peer_id);
+    stream.write_all(&handshake).await?;
+
+    let mut reply = [0u8; HANDSHAKE_LEN];
+    tokio::time::timeout(CONNECT_TIMEOUT, stream.read_exact(&mut reply))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))??;
+    if reply[0] as usize != PROTOCOL.len() || &reply[1..20] != PROTOCOL {
+        return Err(invalid("not a BitTorrent peer".to_string()));
+    }
+    if reply[28..48] != info_hash.0 {
+        return Err(invalid("peer has a different torrent".to_string()));
+    }
+    let their_id = reply[48..68].try_into().unwrap();
+
+    let (reader, writer) = stream.into_split();
+    Ok((Reader(reader), Writer(writer), their_id))
+}
+
+pub struct Reader(OwnedReadHalf);
+
+impl Reader {
+    /// The next message; not cancel safe, so it gets a task of its own.
+    pub async fn read(&mut self) -> io::Result<Message> {
+        let length = self.0.read_u32().await?;
+        if length > MAX_MESSAGE_LEN {
+            return Err(invalid(format!("{}-byte message", length)));
+        }
+        let mut payload = vec![0u8; length as usize];
+        self.0.read_exact(&mut payload).await?;
+        Message::decode(payload)
+    }
+}
+
+pub struct Writer(OwnedWriteHalf);
+
+impl Writer {
+    pub async fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.0.write_all(&message.encode()).await
+    }
+}
+
+/// Whether bit `index` is set in a bitfield.
+pub fn has_piece(bits: &[u8], index: usize) -> bool {
+    bits.get(index / 8)
+        .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
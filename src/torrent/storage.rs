@@ -0,0 +1,130 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::metainfo::Metainfo;
+
+/// Where each file of a torrent lives and where it sits in the stream of
+/// bytes the pieces are cut from.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    files: Vec<Span>,
+    piece_length: u64,
+    total_length: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Span {
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+}
+
+impl Storage {
+    /// A single-file torrent is saved as `dir/name`; a multi-file one gets
+    /// a folder `dir/name` with its files below.
+    pub fn new(dir: &Path, metainfo: &Metainfo) -> Self {
+        let root = match metainfo.multi_file {
+            true => dir.join(&metainfo.name),
+            false => dir.to_path_buf(),
+        };
+        let mut offset = 0;
+        let files = metainfo
+            .files
+            .iter()
+            .map(|file| {
+                let span = Span {
+                    path: file
+                        .path
+                        .iter()
+                        .fold(root.clone(), |path, part| path.join(part)),
+                    offset,
+                    length: file.length,
+                };
+                offset += file.length;
+                span
+            })
+            .collect();
+        Self {
+            files,
+            piece_length: metainfo.piece_length,
+            total_length: metainfo.total_length(),
+        }
+    }
+
+    pub fn piece_size(&self, index: usize) -> u64 {
+        let start = index as u64 * self.piece_length;
+        self.piece_length
+            .min(self.total_length.saturating_sub(start))
+    }
+
+    /// Writes a verified piece across whichever files it spans, creating
+    /// them and their folders as needed. Blocking.
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> io::Result<()> {
+        for (span, file_offset, range) in self.spans(index) {
+            if let Some(parent) = span.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&span.path)?;
+            file.seek(SeekFrom::Start(file_offset))?;
+            file.write_all(&data[range])?;
+        }
+        Ok(())
+    }
+
+    /// The piece as it is on disk, or `None` if some of it isn't there yet.
+    /// Blocking.
+    pub fn read_piece(&self, index: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut data = vec![0u8; self.piece_size(index) as usize];
+        for (span, file_offset, range) in self.spans(index) {
+            let mut file = match File::open(&span.path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if file.metadata()?.len() < file_offset + range.len() as u64 {
+                return Ok(None);
+            }
+            file.seek(SeekFrom::Start(file_offset))?;
+            file.read_exact(&mut data[range])?;
+        }
+        Ok(Some(data))
+    }
+
+    /// Empty files have no pieces to bring them into being.
+    pub fn create_empty_files(&self) -> io::Result<()> {
+        for span in self.files.iter().filter(|span| span.length == 0) {
+            if let Some(parent) = span.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&span.path)?;
+        }
+        Ok(())
+    }
+
+    /// The files piece `index` touches: each with the offset within that
+    /// file and the matching range within the piece.
+    fn spans(&self, index: usize) -> Vec<(&Span, u64, std::ops::Range<usize>)> {
+        let start = index as u64 * self.piece_length;
+        let end = start + self.piece_size(index);
+        self.files
+            .iter()
+            .filter(|span| span.length > 0)
+            .filter(|span| span.offset < end && span.offset + span.length > start)
+            .map(|span| {
+                let from = start.max(span.offset);
+                let to = end.min(span.offset + span.length);
+                let range = (from - start) as usize..(to - start) as usize;
+                (span, from - span.offset, range)
+            })
+            .collect()
+    }
+}
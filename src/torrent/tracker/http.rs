@@ -0,0 +1,190 @@
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use super::{
+    compact_peers, Announce, Error, Response, Scrape, COMPACT_PEER6_LEN, COMPACT_PEER_LEN,
+};
+use crate::torrent::bencode::{self, Value};
+use crate::torrent::metainfo::InfoHash;
+use crate::utils::http;
+
+/// Trackers answer in well under this, or not at all.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// A reply with a few thousand peers fits easily.
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// A `GET` on the announce URL with the torrent and this client's state
+/// in the query (BEP 3), asking for compact peers (BEP 23).
+pub async fn announce(url: &str, request: &Announce) -> Result<Response, Error> {
+    let mut query = format!(
+        "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1&numwant={}&key={:08x}",
+        percent_encode(&request.info_hash.0, NON_ALPHANUMERIC),
+        percent_encode(&request.peer_id, NON_ALPHANUMERIC),
+        request.port,
+        request.stats.uploaded,
+        request.stats.downloaded,
+        request.stats.left,
+        request.num_want,
+        request.key,
+    );
+    if let Some(event) = request.event.as_str() {
+        query.push_str("&event=");
+        query.push_str(event);
+    }
+    if let Some(tracker_id) = &request.tracker_id {
+        query.push_str("&trackerid=");
+        query.extend(percent_encode(tracker_id.as_bytes(), NON_ALPHANUMERIC));
+    }
+
+    let body = get(&with_query(url, &query)).await?;
+    let root = parse(&body)?;
+
+    let interval = |key| {
+        root.get(key)
+            .and_then(Value::as_integer)
+            .and_then(|secs| u64::try_from(secs).ok())
+            .map(Duration::from_secs)
+    };
+    let mut peers = root
+        .get("peers")
+        .map(parse_peers)
+        .transpose()?
+        .unwrap_or_default();
+    if let Some(peers6) = root.get("peers6").and_then(Value::as_bytes) {
+        peers.extend(compact_peers(peers6, COMPACT_PEER6_LEN));
+    }
+
+    Ok(Response {
+        interval: interval("interval").unwrap_or_default(),
+        min_interval: interval("min interval"),
+        peers,
+        seeders: count(&root, "complete"),
+        leechers: count(&root, "incomplete"),
+        warning: root
+            .get("warning message")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        tracker_id: root
+            .get("tracker id")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    })
+}
+
+/// Asks the scrape URL that goes with `url` about one torrent (BEP 48).
+/// Trackers whose announce URL doesn't end in `announce` have none.
+pub async fn scrape(url: &str, info_hash: &InfoHash) -> Result<Option<Scrape>, Error> {
+    let Some(scrape_url) = scrape_url(url) else {
+        return Ok(None);
+    };
+    let query = format!(
+        "info_hash={}",
+        percent_encode(&info_hash.0, NON_ALPHANUMERIC)
+    );
+    let body = get(&with_query(&scrape_url, &query)).await?;
+    let root = parse(&body)?;
+
+    let files = root
+        .get("files")
+        .and_then(Value::as_dict)
+        .ok_or_else(|| Error::Invalid("no files in scrape".to_string()))?;
+    let Some(file) = files.get(&info_hash.0[..]) else {
+        return Ok(None);
+    };
+    Ok(Some(Scrape {
+        seeders: count(file, "complete").unwrap_or_default(),
+        leechers: count(file, "incomplete").unwrap_or_default(),
+        completed: count(file, "downloaded").unwrap_or_default(),
+    }))
+}
+
+/// `.../announce?x` becomes `.../scrape?x`, when the last path part starts
+/// with `announce`.
+fn scrape_url(url: &str) -> Option<String> {
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (url, None),
+    };
+    let (base, last) = path.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+    let mut scrape = format!("{}/scrape{}", base, rest);
+    if let Some(query) = query {
+        scrape.push('?');
+        scrape.push_str(query);
+    }
+    Some(scrape)
+}
+
+/// Adds to whatever query the tracker URL already has, such as a passkey.
+fn with_query(url: &str, query: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", url, separator, query)
+}
+
+async fn get(url: &str) -> Result<Vec<u8>, Error> {
+    let network = |e: reqwest::Error| Error::Network(e.to_string());
+    let response = http::client()
+        .get(url)
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await
+        .map_err(network)?;
+    let status = response.status();
+    if response
+        .content_length()
+        .is_some_and(|len| len > MAX_RESPONSE_SIZE as u64)
+    {
+        return Err(Error::Invalid("response too large".to_string()));
+    }
+    let body = response.bytes().await.map_err(network)?;
+    if body.len() > MAX_RESPONSE_SIZE {
+        return Err(Error::Invalid("response too large".to_string()));
+    }
+    // Some trackers send their `failure reason` with an error status.
+    if !status.is_success() && bencode::decode_relaxed(&body).is_err() {
+        return Err(Error::Network(format!("Tracker responded with {}", status)));
+    }
+    Ok(body.to_vec())
+}
+
+fn parse(body: &[u8]) -> Result<Value<'_>, Error> {
+    let root = bencode::decode_relaxed(body).map_err(|e| Error::Invalid(e.to_string()))?;
+    if root.as_dict().is_none() {
+        return Err(Error::Invalid("not a dictionary".to_string()));
+    }
+    if let Some(reason) = root.get("failure reason") {
+        let reason = String::from_utf8_lossy(reason.as_bytes().unwrap_or_default());
+        return Err(Error::Rejected(reason.into_owned()));
+    }
+    Ok(root)
+}
+
+fn count(dict: &Value, key: &str) -> Option<u32> {
+    dict.get(key)
+        .and_then(Value::as_integer)
+        .and_then(|count| u32::try_from(count).ok())
+}
+
+/// Compact peers as one string, or the original list of dictionaries.
+/// Peers given by host name are skipped rather than looked up.
+fn parse_peers(peers: &Value) -> Result<Vec<SocketAddr>, Error> {
+    if let Some(bytes) = peers.as_bytes() {
+        if bytes.len() % COMPACT_PEER_LEN != 0 {
+            return Err(Error::Invalid("compact peers cut short".to_string()));
+        }
+        return Ok(compact_peers(bytes, COMPACT_PEER_LEN));
+    }
+
+    let peers = peers
+        .as_list()
+        .ok_or_else(|| Error::Invalid("peers are neither a string nor a list".to_string()))?;
+    Ok(peers
+        .iter()
+        .filter_map(|peer| {
+            let ip = peer.get("ip")?.as_str()?.parse::<IpAddr>().ok()?;
+            let port = u16::try_from(peer.get("port")?.as_integer()?).ok()?;
+            Some(SocketAddr::new(ip, port))
+        })
+        .collect())
+}
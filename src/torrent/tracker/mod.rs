@@ -0,0 +1,405 @@
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use super::metainfo::InfoHash;
+
+pub mod http;
+pub mod udp;
+
+/// How many peers to ask each tracker for.
+const NUM_WANT: u32 = 50;
+/// Announces more often than this are ignored, whatever a tracker says.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Used when a tracker leaves out `interval`.
+const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// After a round where every tracker failed, waits double each time up to the cap.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+/// A compact IPv4 peer: four address bytes and a big-endian port (BEP 23).
+pub const COMPACT_PEER_LEN: usize = 6;
+/// The IPv6 form, in `peers6` (BEP 7) or from a UDP tracker reached over IPv6.
+pub const COMPACT_PEER6_LEN: usize = 18;
+/// A `stopped` announce is a courtesy, so it doesn't hold up stopping.
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the announce tells the tracker happened, if anything. The order
+/// is the numbering UDP trackers use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    None,
+    Completed,
+    Started,
+    Stopped,
+}
+
+impl Event {
+    /// The `event` parameter, which a regular announce leaves out.
+    pub fn as_str(self) -> Option<&'static str> {
+        match self {
+            Event::None => None,
+            Event::Completed => Some("completed"),
+            Event::Started => Some("started"),
+            Event::Stopped => Some("stopped"),
+        }
+    }
+}
+
+/// Where this client is with a torrent, as trackers are told it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Announce {
+    pub info_hash: InfoHash,
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub stats: Stats,
+    pub event: Event,
+    pub num_want: u32,
+    /// Identifies this client across IP changes; the same for every announce.
+    pub key: u32,
+    /// Echoed back to a tracker that handed one out.
+    pub tracker_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Response {
+    pub interval: Duration,
+    pub min_interval: Option<Duration>,
+    pub peers: Vec<SocketAddr>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub warning: Option<String>,
+    pub tracker_id: Option<String>,
+}
+
+/// A tracker's counts for one torrent.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Scrape {
+    pub seeders: u32,
+    pub leechers: u32,
+    /// How many times the torrent has been downloaded in full.
+    pub completed: u32,
+}
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    Network(String),
+    /// The tracker answered with a `failure reason`.
+    Rejected(String),
+    /// The answer couldn't be made sense of.
+    Invalid(String),
+    /// A tracker URL this client can't talk to.
+    Unsupported(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Network(msg) => write!(f, "Network error: {}", msg),
+            Error::Rejected(reason) => write!(f, "Tracker refused: {}", reason),
+            Error::Invalid(msg) => write!(f, "Invalid tracker response: {}", msg),
+            Error::Unsupported(url) => write!(f, "Unsupported tracker: {}", url),
+        }
+    }
+}
+
+pub async fn announce(url: &str, request: &Announce) -> Result<Response, Error> {
+    match scheme(url) {
+        Some("http" | "https") => http::announce(url, request).await,
+        Some("udp") => udp::announce(url, request).await,
+        _ => Err(Error::Unsupported(url.to_string())),
+    }
+}
+
+/// Counts for `info_hash`, or `None` if the tracker doesn't do scrapes.
+pub async fn scrape(url: &str, info_hash: &InfoHash) -> Result<Option<Scrape>, Error> {
+    match scheme(url) {
+        Some("http" | "https") => http::scrape(url, info_hash).await,
+        Some("udp") => udp::scrape(url, info_hash).await,
+        _ => Err(Error::Unsupported(url.to_string())),
+    }
+}
+
+/// Peers packed back to back as `len`-byte address and port entries;
+/// a trailing partial entry is dropped.
+pub fn compact_peers(bytes: &[u8], len: usize) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(len)
+        .filter_map(|peer| {
+            let (ip, port) = peer.split_at(len - 2);
+            let ip = match <[u8; 4]>::try_from(ip) {
+                Ok(ip) => IpAddr::V4(Ipv4Addr::from(ip)),
+                Err(_) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?)),
+            };
+            Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+        })
+        .collect()
+}
+
+fn scheme(url: &str) -> Option<&str> {
+    url.split_once("://").map(|(scheme, _)| scheme)
+}
+
+/// How the trackers of a torrent are doing, for the list.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Status {
+    #[default]
+    Idle,
+    Announcing,
+    Working {
+        url: String,
+        peers: usize,
+        seeders: Option<u32>,
+        leechers: Option<u32>,
+        warning: Option<String>,
+    },
+    /// Every tracker failed; this is the last one's error.
+    Failed(String),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Idle => write!(f, "Tracker: not contacted"),
+            Status::Announcing => write!(f, "Tracker: announcing"),
+            Status::Working {
+                url,
+                peers,
+                seeders,
+                leechers,
+                warning,
+            } => {
+                write!(f, "Tracker {}: {} peers", url, peers)?;
+                if let Some(seeders) = seeders {
+                    write!(f, ", {} seeders", seeders)?;
+                }
+                if let Some(leechers) = leechers {
+                    write!(f, ", {} leechers", leechers)?;
+                }
+                if let Some(warning) = warning {
+                    write!(f, " ({})", warning)?;
+                }
+                Ok(())
+            }
+            Status::Failed(msg) => write!(f, "Tracker failed: {}", msg),
+        }
+    }
+}
+
+/// Talks to a torrent's trackers the way BEP 12 has it: tiers in order,
+/// each shuffled once, and a tracker that answers moves to the front of
+/// its tier so it's asked first next time.
+pub struct Announcer {
+    info_hash: InfoHash,
+    tiers: Vec<Vec<String>>,
+    key: u32,
+    tracker_ids: HashMap<String, String>,
+    /// The tracker that last answered, which gets the `stopped` announce.
+    current: Option<String>,
+}
+
+impl Announcer {
+    pub fn new(info_hash: InfoHash, mut tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        tiers.iter_mut().for_each(|tier| tier.shuffle(&mut rng));
+        Self {
+            info_hash,
+            tiers,
+            key: rand::random(),
+            tracker_ids: HashMap::new(),
+            current: None,
+        }
+    }
+
+    /// Announces to the first tracker that answers, returning its URL and answer.
+    pub async fn announce(
+        &mut self,
+        event: Event,
+        stats: Stats,
+    ) -> Result<(String, Response), Error> {
+        let mut last_error = Error::Unsupported("no trackers".to_string());
+        for tier in 0..self.tiers.len() {
+            for index in 0..self.tiers[tier].len() {
+                let url = self.tiers[tier][index].clone();
+                match announce(&url, &self.request(&url, event, stats)).await {
+                    Ok(response) => {
+                        log::info!("{} announced to {}", self.info_hash, url);
+                        if let Some(tracker_id) = &response.tracker_id {
+                            self.tracker_ids.insert(url.clone(), tracker_id.clone());
+                        }
+                        let url = self.tiers[tier].remove(index);
+                        self.tiers[tier].insert(0, url.clone());
+                        self.current = Some(url.clone());
+                        return Ok((url, response));
+                    }
+                    Err(e) => {
+                        log::warn!("Announce to {} failed: {}", url, e);
+                        last_error = e;
+                    }
+                }
+            }
+        }
+        self.current = None;
+        Err(last_error)
+    }
+
+    /// Tells the tracker that last answered that this client is going away.
+    pub async fn stopped(&mut self, stats: Stats) {
+        let Some(url) = self.current.take() else {
+            return;
+        };
+        let request = self.request(&url, Event::Stopped, stats);
+        match tokio::time::timeout(STOPPED_TIMEOUT, announce(&url, &request)).await {
+            Ok(Ok(_)) => log::info!("{} stopped at {}", self.info_hash, url),
+            Ok(Err(e)) => log::warn!("Stopped announce to {} failed: {}", url, e),
+            Err(_) => log::warn!("Stopped announce to {} timed out", url),
+        }
+    }
+
+    /// Counts from the tracker that last answered.
+    pub async fn scrape(&self) -> Option<Scrape> {
+        let url = self.current.as_ref()?;
+        match scrape(url, &self.info_hash).await {
+            Ok(scrape) => scrape,
+            Err(e) => {
+                log::warn!("Scrape of {} failed: {}", url, e);
+                None
+            }
+        }
+    }
+
+    fn request(&self, url: &str, event: Event, stats: Stats) -> Announce {
+        Announce {
+            info_hash: self.info_hash,
+            peer_id: *super::peer_id(),
+            port: super::PORT,
+            stats,
+            event,
+            num_want: match event {
+                Event::Stopped => 0,
+                _ => NUM_WANT,
+            },
+            key: self.key,
+            tracker_id: self.tracker_ids.get(url).cloned(),
+        }
+    }
+}
+
+/// When to announce again after an answer, keeping to `min interval`.
+pub fn next_interval(response: &Response) -> Duration {
+    let interval = match response.interval {
+        Duration::ZERO => DEFAULT_ANNOUNCE_INTERVAL,
+        interval => interval,
+    };
+    interval
+        .max(response.min_interval.unwrap_or_default())
+        .max(MIN_ANNOUNCE_INTERVAL)
+}
+
+/// What the announce loop tells the torrent it runs for.
+#[derive(Debug, Clone)]
+pub enum Update {
+    Status(Status),
+    /// Peers from an answer, to try connecting to.
+    Peers(Vec<SocketAddr>),
+}
+
+/// The torrent's running totals, read at each announce.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub uploaded: AtomicU64,
+    pub downloaded: AtomicU64,
+    pub left: AtomicU64,
+}
+
+impl Counters {
+    fn stats(&self) -> Stats {
+        Stats {
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            left: self.left.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Announces for a running torrent: `started`, then as often as the
+/// tracker asks, and `stopped` when `stop` fires. A torrent that finished
+/// while this ran sends `completed` first. Returns after the `stopped`
+/// announce.
+pub async fn run(
+    mut announcer: Announcer,
+    counters: Arc<Counters>,
+    stop: CancellationToken,
+    updates: mpsc::UnboundedSender<Update>,
+) {
+    let was_complete = counters.stats().left == 0;
+    let mut event = Event::Started;
+    let mut failures = 0;
+    loop {
+        let _ = updates.send(Update::Status(Status::Announcing));
+        let result = tokio::select! {
+            result = announcer.announce(event, counters.stats()) => result,
+            _ = stop.cancelled() => break,
+        };
+        let delay = match result {
+            Ok((url, response)) => {
+                event = Event::None;
+                failures = 0;
+                let (seeders, leechers) = match (response.seeders, response.leechers) {
+                    (None, None) => match announcer.scrape().await {
+                        Some(scrape) => (Some(scrape.seeders), Some(scrape.leechers)),
+                        None => (None, None),
+                    },
+                    counts => counts,
+                };
+                let _ = updates.send(Update::Status(Status::Working {
+                    url,
+                    peers: response.peers.len(),
+                    seeders,
+                    leechers,
+                    warning: response.warning.clone(),
+                }));
+                let delay = next_interval(&response);
+                let _ = updates.send(Update::Peers(response.peers));
+                delay
+            }
+            Err(e) => {
+                // A `started` nobody heard is sent again on the next round.
+                let _ = updates.send(Update::Status(Status::Failed(e.to_string())));
+                let delay = RETRY_DELAY
+                    .saturating_mul(1 << failures.min(10))
+                    .min(MAX_RETRY_DELAY);
+                failures += 1;
+                delay
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stop.cancelled() => break,
+        }
+    }
+    let stats = counters.stats();
+    if !was_complete && stats.left == 0 {
+        let completed = announcer.announce(Event::Completed, stats);
+        if tokio::time::timeout(STOPPED_TIMEOUT, completed)
+            .await
+            .is_err()
+        {
+            log::warn!("Completed announce for {} timed out", announcer.info_hash);
+        }
+    }
+    announcer.stopped(stats).await;
+}
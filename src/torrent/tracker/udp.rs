@@ -0,0 +1,210 @@
+use reqwest::Url;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+use super::{
+    compact_peers, Announce, Error, Response, Scrape, COMPACT_PEER6_LEN, COMPACT_PEER_LEN,
+};
+use crate::torrent::metainfo::InfoHash;
+
+/// Magic number a connect request starts with.
+const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+/// A connection id is good for a minute from when the tracker gave it out.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+/// The spec waits `15 * 2^n` seconds for an answer before sending again.
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+/// The spec goes on to n = 8, which would keep the other trackers waiting
+/// for over an hour; this gives up after about four minutes.
+const MAX_RETRANSMITS: u32 = 3;
+/// Room for a few hundred IPv6 peers.
+const MAX_PACKET_SIZE: usize = 8192;
+/// Action, transaction id, interval, leechers and seeders.
+const ANNOUNCE_HEADER_LEN: usize = 20;
+
+/// Connection ids by tracker address, so a connect isn't needed for every
+/// announce within the minute.
+fn connection_ids() -> &'static Mutex<HashMap<SocketAddr, (u64, Instant)>> {
+    static IDS: OnceLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> = OnceLock::new();
+    IDS.get_or_init(Default::default)
+}
+
+/// The announce of BEP 15: a connect if there's no live connection id,
+/// then a 98-byte request. Peers come back in the address family the
+/// tracker was reached over.
+pub async fn announce(url: &str, request: &Announce) -> Result<Response, Error> {
+    let tracker = Tracker::open(url).await?;
+
+    let mut packet = Vec::with_capacity(98);
+    packet.extend_from_slice(&request.info_hash.0);
+    packet.extend_from_slice(&request.peer_id);
+    packet.extend_from_slice(&request.stats.downloaded.to_be_bytes());
+    packet.extend_from_slice(&request.stats.left.to_be_bytes());
+    packet.extend_from_slice(&request.stats.uploaded.to_be_bytes());
+    packet.extend_from_slice(&(request.event as u32).to_be_bytes());
+    // The tracker takes the address the packet came from.
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.extend_from_slice(&request.key.to_be_bytes());
+    packet.extend_from_slice(&request.num_want.to_be_bytes());
+    packet.extend_from_slice(&request.port.to_be_bytes());
+
+    let reply = tracker.request(ACTION_ANNOUNCE, &packet).await?;
+    if reply.len() < ANNOUNCE_HEADER_LEN {
+        return Err(Error::Invalid("announce reply cut short".to_string()));
+    }
+    let peer_len = match tracker.addr {
+        SocketAddr::V4(_) => COMPACT_PEER_LEN,
+        SocketAddr::V6(_) => COMPACT_PEER6_LEN,
+    };
+    Ok(Response {
+        interval: Duration::from_secs(u64::from(read_u32(&reply, 8))),
+        min_interval: None,
+        peers: compact_peers(&reply[ANNOUNCE_HEADER_LEN..], peer_len),
+        leechers: Some(read_u32(&reply, 12)),
+        seeders: Some(read_u32(&reply, 16)),
+        warning: None,
+        tracker_id: None,
+    })
+}
+
+pub async fn scrape(url: &str, info_hash: &InfoHash) -> Result<Option<Scrape>, Error> {
+    let tracker = Tracker::open(url).await?;
+    let reply = tracker.request(ACTION_SCRAPE, &info_hash.0).await?;
+    if reply.len() < 20 {
+        return Ok(None);
+    }
+    Ok(Some(Scrape {
+        seeders: read_u32(&reply, 8),
+        completed: read_u32(&reply, 12),
+        leechers: read_u32(&reply, 16),
+    }))
+}
+
+/// A socket connected to one tracker.
+struct Tracker {
+    socket: UdpSocket,
+    addr: SocketAddr,
+}
+
+impl Tracker {
+    async fn open(url: &str) -> Result<Self, Error> {
+        let parsed = Url::parse(url).map_err(|_| Error::Unsupported(url.to_string()))?;
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| Error::Unsupported(url.to_string()))?;
+        let port = parsed
+            .port()
+            .ok_or_else(|| Error::Unsupported(url.to_string()))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let addr = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(network)?
+            .next()
+            .ok_or_else(|| Error::Network(format!("No address for {}", host)))?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await.map_err(network)?;
+        socket.connect(addr).await.map_err(network)?;
+        Ok(Self { socket, addr })
+    }
+
+    /// Sends `action` with `body` under a live connection id, and returns
+    /// the reply with its action and transaction id still on the front.
+    async fn request(&self, action: u32, body: &[u8]) -> Result<Vec<u8>, Error> {
+        let connection_id = self.connection_id().await?;
+        let mut packet = Vec::with_capacity(16 + body.len());
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&action.to_be_bytes());
+        let transaction_id: u32 = rand::random();
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(body);
+
+        let reply = self.exchange(&packet, action, transaction_id).await;
+        if reply.is_err() {
+            // The id may have been dropped by the tracker; connect afresh next time.
+            connection_ids().lock().unwrap().remove(&self.addr);
+        }
+        reply
+    }
+
+    async fn connection_id(&self) -> Result<u64, Error> {
+        if let Some((id, issued)) = connection_ids().lock().unwrap().get(&self.addr) {
+            if issued.elapsed() < CONNECTION_ID_TTL {
+                return Ok(*id);
+            }
+        }
+
+        let mut packet = Vec::with_capacity(16);
+        packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        let transaction_id: u32 = rand::random();
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+
+        let reply = self
+            .exchange(&packet, ACTION_CONNECT, transaction_id)
+            .await?;
+        if reply.len() < 16 {
+            return Err(Error::Invalid("connect reply cut short".to_string()));
+        }
+        let id = u64::from_be_bytes(reply[8..16].try_into().unwrap());
+        connection_ids()
+            .lock()
+            .unwrap()
+            .insert(self.addr, (id, Instant::now()));
+        Ok(id)
+    }
+
+    /// Sends `packet` until a reply to `transaction_id` arrives, waiting
+    /// `15 * 2^n` seconds after the nth send. Stray packets are ignored.
+    async fn exchange(
+        &self,
+        packet: &[u8],
+        action: u32,
+        transaction_id: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        for attempt in 0..=MAX_RETRANSMITS {
+            self.socket.send(packet).await.map_err(network)?;
+            let deadline = tokio::time::Instant::now() + BASE_TIMEOUT * (1 << attempt);
+            loop {
+                let received =
+                    match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                        Ok(received) => received.map_err(network)?,
+                        Err(_) => break,
+                    };
+                let reply = &buf[..received];
+                if reply.len() < 8 || read_u32(reply, 4) != transaction_id {
+                    continue;
+                }
+                match read_u32(reply, 0) {
+                    ACTION_ERROR => {
+                        let message = String::from_utf8_lossy(&reply[8..]);
+                        return Err(Error::Rejected(message.into_owned()));
+                    }
+                    reply_action if reply_action == action => return Ok(reply.to_vec()),
+                    _ => continue,
+                }
+            }
+            log::debug!("No answer from {} (attempt {})", self.addr, attempt + 1);
+        }
+        Err(Error::Network(format!("{} didn't answer", self.addr)))
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn network(e: io::Error) -> Error {
+    Error::Network(e.to_string())
+}
@@ -1,2 +1,4 @@
 pub mod modal;
+pub mod schedule_editor;
+pub mod settings_editor;
 pub mod url_input;
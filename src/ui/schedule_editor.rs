@@ -0,0 +1,151 @@
+use iced::{
+    widget::{button, checkbox, column, pick_list, row, text, text_input},
+    Element,
+};
+use std::fmt::{self, Display};
+
+use crate::download_item::throttle;
+use crate::scheduler::{self, Schedule, ScheduleAction, DAY_NAMES};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActionKind {
+    Allow,
+    Pause,
+    Throttle,
+}
+
+impl ActionKind {
+    const ALL: [ActionKind; 3] = [ActionKind::Allow, ActionKind::Pause, ActionKind::Throttle];
+}
+
+impl Display for ActionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionKind::Allow => write!(f, "Only download"),
+            ActionKind::Pause => write!(f, "Pause downloads"),
+            ActionKind::Throttle => write!(f, "Limit speed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ScheduleEditorMessage {
+    ToggleDay(usize, bool),
+    EditStart(String),
+    EditEnd(String),
+    SelectAction(ActionKind),
+    EditLimit(String),
+    Add,
+    Remove(i64),
+}
+
+pub struct ScheduleEditor {
+    days: [bool; 7],
+    start: String,
+    end: String,
+    action: ActionKind,
+    limit: String,
+}
+
+impl Default for ScheduleEditor {
+    fn default() -> Self {
+        Self {
+            days: [true, true, true, true, true, false, false],
+            start: "01:00".to_string(),
+            end: "07:00".to_string(),
+            action: ActionKind::Allow,
+            limit: String::new(),
+        }
+    }
+}
+
+impl ScheduleEditor {
+    pub fn update(&mut self, message: ScheduleEditorMessage) {
+        match message {
+            ScheduleEditorMessage::ToggleDay(day, checked) => self.days[day] = checked,
+            ScheduleEditorMessage::EditStart(start) => self.start = start,
+            ScheduleEditorMessage::EditEnd(end) => self.end = end,
+            ScheduleEditorMessage::SelectAction(action) => self.action = action,
+            ScheduleEditorMessage::EditLimit(limit) => self.limit = limit,
+            // Adding and removing change the saved schedules, which the app owns.
+            ScheduleEditorMessage::Add | ScheduleEditorMessage::Remove(_) => {}
+        }
+    }
+
+    /// The schedule described by the form, if it is complete and valid.
+    pub fn schedule(&self) -> Option<Schedule> {
+        let days = self
+            .days
+            .iter()
+            .enumerate()
+            .filter(|(_, checked)| **checked)
+            .fold(0u8, |days, (day, _)| days | (1 << day));
+        if days == 0 {
+            return None;
+        }
+
+        let action = match self.action {
+            ActionKind::Allow => ScheduleAction::Allow,
+            ActionKind::Pause => ScheduleAction::Pause,
+            ActionKind::Throttle => ScheduleAction::Throttle(throttle::parse_limit(&self.limit)??),
+        };
+
+        Some(Schedule {
+            id: 0,
+            days,
+            start: scheduler::parse_time(&self.start)?,
+            end: scheduler::parse_time(&self.end)?,
+            action,
+        })
+    }
+
+    pub fn view<'a>(&'a self, schedules: &'a [Schedule]) -> Element<'a, ScheduleEditorMessage> {
+        let saved = column(schedules.iter().map(|schedule| {
+            row![
+                text(schedule.to_string()),
+                button("remove").on_press(ScheduleEditorMessage::Remove(schedule.id)),
+            ]
+            .spacing(10)
+            .into()
+        }))
+        .spacing(5);
+
+        let days = row(DAY_NAMES.iter().enumerate().map(|(day, name)| {
+            checkbox(*name, self.days[day])
+                .on_toggle(move |checked| ScheduleEditorMessage::ToggleDay(day, checked))
+                .into()
+        }))
+        .spacing(10);
+
+        let mut form = row![
+            text_input("HH:MM", &self.start)
+                .on_input(ScheduleEditorMessage::EditStart)
+                .width(70),
+            text("to"),
+            text_input("HH:MM", &self.end)
+                .on_input(ScheduleEditorMessage::EditEnd)
+                .width(70),
+            pick_list(
+                ActionKind::ALL,
+                Some(self.action),
+                ScheduleEditorMessage::SelectAction
+            ),
+        ]
+        .spacing(10);
+        if self.action == ActionKind::Throttle {
+            form = form.push(
+                text_input("KB/s", &self.limit)
+                    .on_input(ScheduleEditorMessage::EditLimit)
+                    .width(80),
+            );
+        }
+        form = form.push(
+            button("Add schedule")
+                .on_press_maybe(self.schedule().map(|_| ScheduleEditorMessage::Add)),
+        );
+
+        column![text("Schedules"), saved, days, form]
+            .spacing(10)
+            .into()
+    }
+}
@@ -0,0 +1,137 @@
+use iced::{
+    widget::{button, checkbox, column, pick_list, row, text, text_input},
+    Element,
+};
+
+use crate::download_item::collision::CollisionPolicy;
+use crate::settings::{Category, Settings};
+use crate::utils::proxy::ProxyRules;
+
+#[derive(Debug, Clone)]
+pub enum SettingsMessage {
+    EditDownloadDir(String),
+    EditCategoryDir(Category, String),
+    SelectCollisionPolicy(CollisionPolicy),
+    ToggleRestartUnresumable(bool),
+    EditProxy(String),
+    EditNoProxy(String),
+    AddProxyRule,
+    EditProxyRuleHost(usize, String),
+    EditProxyRuleProxy(usize, String),
+    RemoveProxyRule(usize),
+}
+
+/// Applies an edit from the settings panel.
+pub fn update(settings: &mut Settings, message: SettingsMessage) {
+    match message {
+        SettingsMessage::EditDownloadDir(dir) => settings.download_dir = dir,
+        SettingsMessage::EditCategoryDir(category, dir) => {
+            settings.category_dirs.insert(category, dir);
+        }
+        SettingsMessage::SelectCollisionPolicy(policy) => settings.collision_policy = policy,
+        SettingsMessage::ToggleRestartUnresumable(restart) => {
+            settings.restart_unresumable = restart;
+        }
+        SettingsMessage::EditProxy(proxy) => settings.proxy = proxy,
+        SettingsMessage::EditNoProxy(no_proxy) => settings.no_proxy = no_proxy,
+        SettingsMessage::AddProxyRule => settings.proxy_rules.push(Default::default()),
+        SettingsMessage::EditProxyRuleHost(index, host) => {
+            if let Some(rule) = settings.proxy_rules.get_mut(index) {
+                rule.0 = host;
+            }
+        }
+        SettingsMessage::EditProxyRuleProxy(index, proxy) => {
+            if let Some(rule) = settings.proxy_rules.get_mut(index) {
+                rule.1 = proxy;
+            }
+        }
+        SettingsMessage::RemoveProxyRule(index) => {
+            if index < settings.proxy_rules.len() {
+                settings.proxy_rules.remove(index);
+            }
+        }
+    }
+}
+
+pub fn view(settings: &Settings) -> Element<'_, SettingsMessage> {
+    let categories = column(Category::ALL.into_iter().map(|category| {
+        let dir = settings
+            .category_dirs
+            .get(&category)
+            .map(String::as_str)
+            .unwrap_or_default();
+        row![
+            text(category.to_string()).width(100),
+            text_input("Same as downloads", dir)
+                .on_input(move |dir| SettingsMessage::EditCategoryDir(category, dir)),
+        ]
+        .spacing(10)
+        .into()
+    }))
+    .spacing(5);
+
+    let proxy_rules = column(settings.proxy_rules.iter().enumerate().map(
+        |(index, (host, proxy))| {
+            row![
+                text_input("Host, e.g. *.example.com", host)
+                    .on_input(move |host| SettingsMessage::EditProxyRuleHost(index, host))
+                    .width(200),
+                text_input("Proxy URL or direct", proxy)
+                    .on_input(move |proxy| SettingsMessage::EditProxyRuleProxy(index, proxy)),
+                button("Remove").on_press(SettingsMessage::RemoveProxyRule(index)),
+            ]
+            .spacing(10)
+            .into()
+        },
+    ))
+    .spacing(5);
+    let proxy_error = ProxyRules::from_settings(settings).err();
+
+    column![
+        text("Settings"),
+        row![
+            text("Downloads").width(100),
+            text_input("Download folder", &settings.download_dir)
+                .on_input(SettingsMessage::EditDownloadDir),
+        ]
+        .spacing(10),
+        categories,
+        row![
+            text("If file exists:"),
+            pick_list(
+                CollisionPolicy::ALL,
+                Some(settings.collision_policy),
+                SettingsMessage::SelectCollisionPolicy
+            ),
+        ]
+        .spacing(10),
+        checkbox(
+            "Restart downloads that can't be resumed without asking",
+            settings.restart_unresumable
+        )
+        .on_toggle(SettingsMessage::ToggleRestartUnresumable),
+        row![
+            text("Proxy").width(100),
+            text_input(
+                "From the environment, or e.g. http://proxy:3128, socks5://proxy:1080",
+                &settings.proxy
+            )
+            .on_input(SettingsMessage::EditProxy),
+        ]
+        .spacing(10),
+        row![
+            text("No proxy for").width(100),
+            text_input(
+                "Hosts, e.g. localhost, .internal.example.com",
+                &settings.no_proxy
+            )
+            .on_input(SettingsMessage::EditNoProxy),
+        ]
+        .spacing(10),
+        proxy_rules,
+        button("Add proxy rule").on_press(SettingsMessage::AddProxyRule),
+        text(proxy_error.unwrap_or_default()),
+    ]
+    .spacing(10)
+    .into()
+}
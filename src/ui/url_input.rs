@@ -1,15 +1,58 @@
 use iced::{
-    widget::{button, row, text_input},
+    widget::{button, checkbox, column, pick_list, row, text, text_input},
     Element, Task,
 };
 use log::debug;
 use reqwest::Url;
+use std::fmt::{self, Display};
 
+use crate::download_item::auth::Credentials;
+use crate::download_item::checksum::Checksum;
+use crate::download_item::collision::CollisionPolicy;
+use crate::download_item::ftp;
+use crate::download_item::headers::{self, RequestHeaders};
+use crate::download_item::metalink;
+use crate::torrent::metainfo;
 use crate::utils::{debounce::DebouncedInput, http::get_downloadable_content_type};
 
+/// A collision policy for one download, or `None` to follow the global one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PolicyChoice(Option<CollisionPolicy>);
+
+impl PolicyChoice {
+    fn all() -> Vec<PolicyChoice> {
+        std::iter::once(PolicyChoice(None))
+            .chain(CollisionPolicy::ALL.map(|policy| PolicyChoice(Some(policy))))
+            .collect()
+    }
+}
+
+impl Display for PolicyChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(policy) => policy.fmt(f),
+            None => write!(f, "Global default"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum UrlInputMessage {
     Edit(String),
+    EditChecksum(String),
+    EditMirrors(String),
+    SelectCollisionPolicy(PolicyChoice),
+    EditDownloadDir(String),
+    ToggleRequestOptions,
+    EditUserAgent(String),
+    EditReferer(String),
+    EditCookie(String),
+    EditHeader(String),
+    AddHeader,
+    RemoveHeader(usize),
+    EditUsername(String),
+    EditPassword(String),
+    RememberLogin(bool),
     Add,
     Validated(Option<String>),
     CheckValidation(String),
@@ -19,6 +62,31 @@ pub enum UrlInputMessage {
 pub struct UrlInput {
     pub value: String,
     pub content_type: Option<String>,
+    /// Optional expected digest, `sha256:<hex>` or bare hex.
+    pub checksum: String,
+    /// More URLs for the same file, separated by whitespace.
+    pub mirrors: String,
+    /// Overrides the global collision policy for this download.
+    pub collision_policy: Option<CollisionPolicy>,
+    /// Folder to save into; empty leaves it to the settings.
+    pub download_dir: String,
+    /// Whether the header, cookie, user agent and referer fields are shown.
+    pub show_request_options: bool,
+    pub user_agent: String,
+    pub referer: String,
+    pub cookie: String,
+    /// Extra headers added so far.
+    pub headers: Vec<(String, String)>,
+    /// The `Name: value` line being typed for the next header.
+    pub header: String,
+    pub username: String,
+    pub password: String,
+    /// Keep the login for every download from the host, not just this one.
+    pub remember_login: bool,
+    /// A metalink is being fetched and read.
+    pub loading: bool,
+    /// Why the last add didn't work.
+    pub error: Option<String>,
     debouncer: DebouncedInput<UrlInputMessage>,
     is_validating: bool,
     validation_handle: Option<iced::task::Handle>,
@@ -29,6 +97,21 @@ impl Default for UrlInput {
         Self {
             value: String::new(),
             content_type: None,
+            checksum: String::new(),
+            mirrors: String::new(),
+            collision_policy: None,
+            download_dir: String::new(),
+            show_request_options: false,
+            user_agent: String::new(),
+            referer: String::new(),
+            cookie: String::new(),
+            headers: Vec::new(),
+            header: String::new(),
+            username: String::new(),
+            password: String::new(),
+            remember_login: false,
+            loading: false,
+            error: None,
             debouncer: DebouncedInput::new(500),
             is_validating: false,
             validation_handle: None,
@@ -37,6 +120,79 @@ impl Default for UrlInput {
 }
 
 impl UrlInput {
+    /// The mirror URLs as entered, minus any repeat of the main one.
+    pub fn mirror_urls(&self) -> Vec<String> {
+        let mut mirrors: Vec<String> = Vec::new();
+        for url in self.mirrors.split_whitespace() {
+            if url != self.value && !mirrors.iter().any(|mirror| mirror == url) {
+                mirrors.push(url.to_string());
+            }
+        }
+        mirrors
+    }
+
+    pub fn clear(&mut self) {
+        self.value.clear();
+        self.checksum.clear();
+        self.mirrors.clear();
+        self.collision_policy = None;
+        self.download_dir.clear();
+        self.show_request_options = false;
+        self.user_agent.clear();
+        self.referer.clear();
+        self.cookie.clear();
+        self.headers.clear();
+        self.header.clear();
+        self.username.clear();
+        self.password.clear();
+        self.remember_login = false;
+        self.loading = false;
+        self.error = None;
+    }
+
+    /// The headers to send for the download, leaving out empty fields.
+    pub fn request_headers(&self) -> RequestHeaders {
+        let field = |value: &str| Some(value.trim().to_string()).filter(|v| !v.is_empty());
+        RequestHeaders {
+            headers: self.headers.clone(),
+            cookie: field(&self.cookie),
+            user_agent: field(&self.user_agent),
+            referer: field(&self.referer),
+            credentials: (!self.username.trim().is_empty()).then(|| Credentials {
+                username: self.username.trim().to_string(),
+                password: self.password.clone(),
+            }),
+        }
+    }
+
+    /// Checks the URL again with the headers as they now are, since a portal
+    /// may only answer once it gets the right cookie or referer.
+    fn revalidate(&mut self) -> Task<UrlInputMessage> {
+        if self.value.is_empty() {
+            return Task::none();
+        }
+        if let Some(handle) = self.validation_handle.take() {
+            handle.abort();
+        }
+        self.content_type = None;
+        self.is_validating = true;
+        self.debouncer.debounce(
+            UrlInputMessage::CheckValidation(self.value.clone()),
+            |msg| msg,
+        )
+    }
+
+    /// Whether what was entered is a metalink rather than the file itself.
+    pub fn is_metalink(&self) -> bool {
+        metalink::is_metalink(self.value.trim(), self.content_type.as_deref())
+    }
+
+    /// Whether what was entered is a `.torrent` file, which becomes a
+    /// torrent rather than a download.
+    pub fn is_torrent(&self) -> bool {
+        metainfo::is_torrent(self.value.trim(), self.content_type.as_deref())
+    }
+
     pub fn update(&mut self, message: UrlInputMessage) -> Task<UrlInputMessage> {
         match message {
             UrlInputMessage::Edit(url) => {
@@ -45,26 +201,115 @@ impl UrlInput {
                 }
                 self.value = url.clone();
                 self.content_type = None;
+                self.error = None;
                 self.is_validating = true;
                 self.debouncer
                     .debounce(UrlInputMessage::CheckValidation(url), |msg| msg)
             }
+            UrlInputMessage::EditChecksum(checksum) => {
+                self.checksum = checksum;
+                Task::none()
+            }
+            UrlInputMessage::EditMirrors(mirrors) => {
+                self.mirrors = mirrors;
+                Task::none()
+            }
+            UrlInputMessage::EditDownloadDir(dir) => {
+                self.download_dir = dir;
+                Task::none()
+            }
+            UrlInputMessage::SelectCollisionPolicy(PolicyChoice(policy)) => {
+                self.collision_policy = policy;
+                Task::none()
+            }
+            UrlInputMessage::ToggleRequestOptions => {
+                self.show_request_options = !self.show_request_options;
+                Task::none()
+            }
+            UrlInputMessage::EditUserAgent(user_agent) => {
+                self.user_agent = user_agent;
+                self.revalidate()
+            }
+            UrlInputMessage::EditReferer(referer) => {
+                self.referer = referer;
+                self.revalidate()
+            }
+            UrlInputMessage::EditCookie(cookie) => {
+                self.cookie = cookie;
+                self.revalidate()
+            }
+            UrlInputMessage::EditHeader(header) => {
+                self.header = header;
+                Task::none()
+            }
+            UrlInputMessage::AddHeader => match headers::parse_header(&self.header) {
+                Some(header) => {
+                    self.headers.push(header);
+                    self.header.clear();
+                    self.revalidate()
+                }
+                None => Task::none(),
+            },
+            UrlInputMessage::EditUsername(username) => {
+                self.username = username;
+                self.revalidate()
+            }
+            UrlInputMessage::EditPassword(password) => {
+                self.password = password;
+                self.revalidate()
+            }
+            UrlInputMessage::RememberLogin(remember) => {
+                self.remember_login = remember;
+                Task::none()
+            }
+            UrlInputMessage::RemoveHeader(index) => {
+                if index < self.headers.len() {
+                    self.headers.remove(index);
+                }
+                self.revalidate()
+            }
             UrlInputMessage::Validated(content_type) => {
                 debug!("Validated content type: {:?}", content_type);
                 self.content_type = content_type;
                 self.is_validating = false;
                 Task::none()
             }
+            // Local metalinks can't be asked for a content type, and servers
+            // often send remote ones as plain XML. Loading them is the check.
+            UrlInputMessage::CheckValidation(url) if metalink::is_metalink(url.trim(), None) => {
+                self.update(UrlInputMessage::Validated(Some(
+                    metalink::CONTENT_TYPE.to_string(),
+                )))
+            }
+            // The same goes for torrents, which are parsed before being added.
+            UrlInputMessage::CheckValidation(url) if metainfo::is_torrent(url.trim(), None) => self
+                .update(UrlInputMessage::Validated(Some(
+                    metainfo::CONTENT_TYPE.to_string(),
+                ))),
+            // FTP has no content types, so a file the server knows of is
+            // taken as one it will send.
+            UrlInputMessage::CheckValidation(url) if ftp::is_ftp(url.trim()) => {
+                let headers = self.request_headers();
+                let (task, handle) = Task::abortable(Task::future(async move {
+                    let url = url.trim();
+                    ftp::probe(url, url, &headers)
+                        .await
+                        .ok()
+                        .map(|_| ftp::CONTENT_TYPE.to_string())
+                }));
+                self.validation_handle = Some(handle);
+                task.map(UrlInputMessage::Validated)
+            }
             UrlInputMessage::CheckValidation(url) => {
+                let headers = self.request_headers();
                 let (task, handle) = Task::abortable(Task::future(async move {
-                    get_downloadable_content_type(&url).await
+                    get_downloadable_content_type(&url, &headers).await
                 }));
                 self.validation_handle = Some(handle);
                 task.map(UrlInputMessage::Validated)
             }
             UrlInputMessage::ClipboardContent(Some(content)) if !content.is_empty() => {
-                let url_result = Url::parse(&content);
-                if url_result.is_ok() && matches!(url_result.unwrap().scheme(), "http" | "https") {
+                if is_download_url(&content) {
                     self.value = content.clone();
                     self.is_validating = true;
                     self.debouncer
@@ -79,18 +324,107 @@ impl UrlInput {
         }
     }
 
-    pub fn view(&self) -> Element<UrlInputMessage> {
-        row![
-            text_input("Enter URL...", &self.value).on_input(UrlInputMessage::Edit),
-            if self.is_validating {
-                button("Validating...").into()
+    pub fn view(&self) -> Element<'_, UrlInputMessage> {
+        let checksum_valid =
+            self.checksum.trim().is_empty() || Checksum::parse(&self.checksum).is_some();
+        let mirrors_valid = self.mirrors.split_whitespace().all(is_download_url);
+
+        column![
+            row![
+                text_input("Enter URL...", &self.value).on_input(UrlInputMessage::Edit),
+                if self.is_validating {
+                    button("Validating...")
+                } else if self.loading {
+                    button("Loading...")
+                } else {
+                    button("Add").on_press_maybe(
+                        (self.content_type.is_some()
+                            && !self.value.is_empty()
+                            && checksum_valid
+                            && mirrors_valid)
+                            .then_some(UrlInputMessage::Add),
+                    )
+                }
+            ],
+            text(self.error.as_deref().unwrap_or_default()),
+            text_input("Expected checksum (optional)...", &self.checksum)
+                .on_input(UrlInputMessage::EditChecksum),
+            text_input(
+                "Mirror URLs, separated by spaces (optional)...",
+                &self.mirrors
+            )
+            .on_input(UrlInputMessage::EditMirrors),
+            text_input("Save to (default folder)...", &self.download_dir)
+                .on_input(UrlInputMessage::EditDownloadDir),
+            row![
+                text("If file exists:"),
+                pick_list(
+                    PolicyChoice::all(),
+                    Some(PolicyChoice(self.collision_policy)),
+                    UrlInputMessage::SelectCollisionPolicy
+                ),
+            ]
+            .spacing(10),
+            button(if self.show_request_options {
+                "Hide request options"
             } else {
-                button("Add").on_press_maybe(
-                    (self.content_type.is_some() && !self.value.is_empty())
-                        .then_some(UrlInputMessage::Add),
-                )
-            }
+                "Request options..."
+            })
+            .on_press(UrlInputMessage::ToggleRequestOptions),
+        ]
+        .push_maybe(self.show_request_options.then(|| self.request_options()))
+        .into()
+    }
+
+    fn request_options(&self) -> Element<'_, UrlInputMessage> {
+        let header_valid = headers::parse_header(&self.header).is_some();
+
+        column![
+            text_input("User-Agent (default)...", &self.user_agent)
+                .on_input(UrlInputMessage::EditUserAgent),
+            text_input("Referer (optional)...", &self.referer)
+                .on_input(UrlInputMessage::EditReferer),
+            text_input("Cookie, e.g. session=abc (optional)...", &self.cookie)
+                .on_input(UrlInputMessage::EditCookie),
+            row![
+                text_input("User name (optional)...", &self.username)
+                    .on_input(UrlInputMessage::EditUsername),
+                text_input("Password...", &self.password)
+                    .secure(true)
+                    .on_input(UrlInputMessage::EditPassword),
+                checkbox("Remember for this host", self.remember_login)
+                    .on_toggle(UrlInputMessage::RememberLogin),
+            ]
+            .spacing(10),
+            row![
+                text_input("Header, e.g. Authorization: Bearer ...", &self.header)
+                    .on_input(UrlInputMessage::EditHeader)
+                    .on_submit_maybe(header_valid.then_some(UrlInputMessage::AddHeader)),
+                button("Add header")
+                    .on_press_maybe(header_valid.then_some(UrlInputMessage::AddHeader)),
+            ],
         ]
+        .extend(
+            self.headers
+                .iter()
+                .enumerate()
+                .map(|(index, (name, value))| {
+                    row![
+                        text(format!("{}: {}", name, value)),
+                        button("Remove").on_press(UrlInputMessage::RemoveHeader(index)),
+                    ]
+                    .spacing(10)
+                    .into()
+                }),
+        )
         .into()
     }
 }
+
+fn is_http_url(value: &str) -> bool {
+    Url::parse(value).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+fn is_download_url(value: &str) -> bool {
+    is_http_url(value) || ftp::is_ftp(value)
+}
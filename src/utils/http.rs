@@ -1,12 +1,36 @@
 use reqwest::{Client, Url};
+use std::sync::OnceLock;
+use std::time::Duration;
 
-pub async fn get_downloadable_content_type(url: &str) -> Option<String> {
-    let client = Client::builder()
-        .redirect(reqwest::redirect::Policy::limited(5))
-        .build()
-        .ok()?;
+use super::proxy;
+use crate::download_item::headers::RequestHeaders;
 
-    let response = client.get(Url::parse(url).ok()?).send().await.ok()?;
+/// Sent unless a download sets its own; some servers turn away requests
+/// without one.
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// How long an idle pooled connection is kept around for the next request.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// The client every request goes through, so connections to a host are
+/// pooled and reused across probes, segments and downloads, and the proxy
+/// rules apply to all of them.
+pub fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .user_agent(USER_AGENT)
+            .proxy(reqwest::Proxy::custom(proxy::for_url))
+            .redirect(reqwest::redirect::Policy::limited(10))
+            .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+            .build()
+            .expect("Failed to build HTTP client")
+    })
+}
+
+pub async fn get_downloadable_content_type(url: &str, headers: &RequestHeaders) -> Option<String> {
+    let request = client().get(Url::parse(url).ok()?);
+    let response = headers.send(request, url, url).await.ok()?;
 
     if let Some(content_type) = response
         .headers()
@@ -0,0 +1,318 @@
+use base64::Engine;
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{OnceLock, RwLock};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::settings::Settings;
+
+/// Where SOCKS proxies listen unless told otherwise.
+const SOCKS_PORT: u16 = 1080;
+/// A `CONNECT` reply is a status line and a few headers.
+const MAX_CONNECT_RESPONSE: usize = 16 * 1024;
+
+/// How to reach a host.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+    Direct,
+    /// An `http(s)://` proxy, tunnelling with `CONNECT` for https, or a
+    /// `socks5h://` one, which also looks the host name up.
+    Proxy(Url),
+}
+
+/// Which proxy each request goes through, from the settings.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyRules {
+    /// Checked in order; the first pattern matching the host wins.
+    hosts: Vec<(String, Route)>,
+    /// Hosts always reached directly.
+    no_proxy: Vec<String>,
+    /// For hosts nothing else covers; `None` leaves it to the environment's
+    /// `HTTPS_PROXY`, `http_proxy`, `ALL_PROXY` and `NO_PROXY`.
+    default: Option<Route>,
+}
+
+impl ProxyRules {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let default = match settings.proxy.trim() {
+            "" => None,
+            proxy => Some(parse_route(proxy).map_err(|e| format!("Proxy: {}", e))?),
+        };
+        let hosts = settings
+            .proxy_rules
+            .iter()
+            .filter(|(pattern, _)| !pattern.trim().is_empty())
+            .map(|(pattern, proxy)| {
+                let route =
+                    parse_route(proxy).map_err(|e| format!("Proxy for {}: {}", pattern, e))?;
+                Ok((pattern.trim().to_ascii_lowercase(), route))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            hosts,
+            no_proxy: split_list(&settings.no_proxy),
+            default,
+        })
+    }
+
+    /// The proxy to send a request for `url` through, if any.
+    pub fn route(&self, url: &Url) -> Option<Url> {
+        let host = url
+            .host_str()?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase();
+        if self.no_proxy.iter().any(|pattern| matches(pattern, &host)) {
+            return None;
+        }
+        let route = self
+            .hosts
+            .iter()
+            .find(|(pattern, _)| matches(pattern, &host))
+            .map(|(_, route)| route)
+            .or(self.default.as_ref());
+        match route {
+            Some(Route::Proxy(proxy)) => Some(proxy.clone()),
+            Some(Route::Direct) => None,
+            None => from_env(url, &host),
+        }
+    }
+}
+
+/// The rules every client uses, which can change while it's running.
+fn rules() -> &'static RwLock<ProxyRules> {
+    static RULES: OnceLock<RwLock<ProxyRules>> = OnceLock::new();
+    RULES.get_or_init(Default::default)
+}
+
+pub fn set(proxy_rules: ProxyRules) {
+    *rules().write().unwrap() = proxy_rules;
+}
+
+/// The proxy for `url` under the current rules.
+pub fn for_url(url: &Url) -> Option<Url> {
+    rules().read().unwrap().route(url)
+}
+
+/// `direct` (or `none`), or a proxy URL. A plain `host:port` is taken as an
+/// HTTP proxy, and `socks5://` always resolves names on the proxy, so hosts
+/// only it can see still work.
+fn parse_route(value: &str) -> Result<Route, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("direct") || value.eq_ignore_ascii_case("none") {
+        return Ok(Route::Direct);
+    }
+    let value = match value.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("socks5") => {
+            format!("socks5h://{}", rest)
+        }
+        Some(_) => value.to_string(),
+        None => format!("http://{}", value),
+    };
+    let url = Url::parse(&value).map_err(|e| format!("{} ({})", value, e))?;
+    if !matches!(url.scheme(), "http" | "https" | "socks5h") {
+        return Err(format!("{} isn't an http, https or socks5 proxy", value));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err(format!("{} has no host", value));
+    }
+    Ok(Route::Proxy(url))
+}
+
+/// `example.com` and `.example.com` cover the domain and everything under
+/// it, `*.example.com` only what's under it, and `*` every host.
+fn matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim().to_ascii_lowercase();
+    if pattern == "*" {
+        return true;
+    }
+    if let Some(domain) = pattern.strip_prefix("*.") {
+        return host.ends_with(&format!(".{}", domain));
+    }
+    let domain = pattern.trim_start_matches('.');
+    !domain.is_empty() && (host == domain || host.ends_with(&format!(".{}", domain)))
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split([',', ' ', '\n'])
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// What curl and most tools would use, going by the usual variables.
+fn from_env(url: &Url, host: &str) -> Option<Url> {
+    let var = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+    };
+    if let Some(no_proxy) = var(&["NO_PROXY", "no_proxy"]) {
+        if split_list(&no_proxy)
+            .iter()
+            .any(|pattern| matches(pattern, host))
+        {
+            return None;
+        }
+    }
+    let proxy = match url.scheme() {
+        "https" => var(&["HTTPS_PROXY", "https_proxy"]),
+        // Upper case isn't trusted for plain http, as CGI sets it from a header.
+        "http" => var(&["http_proxy"]),
+        _ => None,
+    }
+    .or_else(|| var(&["ALL_PROXY", "all_proxy"]))?;
+    match parse_route(&proxy) {
+        Ok(Route::Proxy(proxy)) => Some(proxy),
+        _ => None,
+    }
+}
+
+/// Opens a TCP connection to `host:port` on behalf of `url`, through its
+/// proxy if the rules give it one: SOCKS5 with the name resolved by the
+/// proxy, or an HTTP proxy's `CONNECT` tunnel. For protocols that don't go
+/// through the HTTP client, like FTP.
+pub async fn connect(url: &Url, host: &str, port: u16) -> io::Result<TcpStream> {
+    let Some(proxy) = for_url(url) else {
+        return TcpStream::connect((host, port)).await;
+    };
+    let proxy_host = proxy.host_str().unwrap_or_default();
+    let mut stream = match proxy.port_or_known_default() {
+        Some(proxy_port) => TcpStream::connect((proxy_host, proxy_port)).await?,
+        None => TcpStream::connect((proxy_host, SOCKS_PORT)).await?,
+    };
+    match proxy.scheme() {
+        "socks5h" => socks5_connect(&mut stream, &proxy, host, port).await?,
+        "http" => http_connect(&mut stream, &proxy, host, port).await?,
+        scheme => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Can't tunnel through a {} proxy", scheme),
+            ))
+        }
+    }
+    Ok(stream)
+}
+
+async fn socks5_connect(
+    stream: &mut TcpStream,
+    proxy: &Url,
+    host: &str,
+    port: u16,
+) -> io::Result<()> {
+    let failed = |message: &str| io::Error::other(format!("SOCKS5: {}", message));
+    let username = percent_decode_str(proxy.username()).decode_utf8_lossy();
+    let password = percent_decode_str(proxy.password().unwrap_or_default()).decode_utf8_lossy();
+
+    // Offer no authentication, and a user name and password if we have them.
+    if username.is_empty() {
+        stream.write_all(&[5, 1, 0]).await?;
+    } else {
+        stream.write_all(&[5, 2, 0, 2]).await?;
+    }
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    match reply {
+        [5, 0] => {}
+        [5, 2] if !username.is_empty() => {
+            let mut request = vec![1, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(failed("login rejected"));
+            }
+        }
+        _ => return Err(failed("no acceptable authentication method")),
+    }
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let name = host.as_bytes();
+            if name.len() > u8::MAX as usize {
+                return Err(failed("host name too long"));
+            }
+            request.push(3);
+            request.push(name.len() as u8);
+            request.extend_from_slice(name);
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(failed(&format!("connect refused ({})", reply[1])));
+    }
+    // Skip the address the proxy bound, which we have no use for.
+    let address_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err(failed("bad reply")),
+    };
+    let mut address = vec![0u8; address_len + 2];
+    stream.read_exact(&mut address).await?;
+    Ok(())
+}
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    proxy: &Url,
+    host: &str,
+    port: u16,
+) -> io::Result<()> {
+    let mut request = format!(
+        "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+        host = host,
+        port = port
+    );
+    if !proxy.username().is_empty() {
+        let login = format!(
+            "{}:{}",
+            percent_decode_str(proxy.username()).decode_utf8_lossy(),
+            percent_decode_str(proxy.password().unwrap_or_default()).decode_utf8_lossy()
+        );
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::engine::general_purpose::STANDARD.encode(login)
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response a byte at a time, so nothing past it is swallowed.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > MAX_CONNECT_RESPONSE {
+            return Err(io::Error::other("Proxy response too long"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "Proxy refused the tunnel: {}",
+            status_line
+        ))),
+    }
+}